@@ -1,10 +1,9 @@
 use clap::Parser;
-use std::{
-    net::{Ipv4Addr, SocketAddrV4},
-    path::PathBuf,
-    time::Duration,
+use std::{path::PathBuf, time::Duration};
+use woonsocket::{
+    app::Work, closed_loop_client, config::ClientConfig, handshake::Codec, handshake::ConnParams,
+    handshake::Features, handshake::FramingMode, open_loop_client, sockopt::SocketOptions, transport::Endpoint, udp_client,
 };
-use woonsocket::{app::Work, closed_loop_client, open_loop_client};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about=None)]
@@ -18,25 +17,108 @@ pub struct Opt {
     #[arg(short, long)]
     runtime_secs: u64,
 
-    #[arg(short, long)]
-    ip: Ipv4Addr,
+    #[arg(short, long, alias = "host", required_unless_present_any = ["unix", "shm"], help = "Server address or host name, IPv4 or IPv6")]
+    ip: Option<String>,
 
-    #[arg(short, long)]
-    port: u16,
+    #[arg(short, long, required_unless_present_any = ["unix", "shm"])]
+    port: Option<u16>,
+
+    #[arg(long, conflicts_with_all = ["ip", "port"], help = "Connect to a Unix domain socket at this path instead of TCP")]
+    unix: Option<PathBuf>,
+
+    #[arg(long, conflicts_with_all = ["ip", "port", "unix"], help = "Connect to a shared-memory server taking clients at this path")]
+    shm: Option<PathBuf>,
+
+    #[arg(long, requires = "interval_us", conflicts_with_all = ["unix", "shm"], help = "Send each request in its own UDP datagram (open loop only)")]
+    udp: bool,
+
+    #[arg(long, default_value_t = 1000, help = "Over UDP, count a request as lost if unanswered after this many milliseconds")]
+    response_timeout_ms: u64,
 
     #[arg(short, long)]
     work: Work,
 
     #[arg(short, long)]
     outpath: PathBuf,
+
+    #[arg(long, value_enum, default_value_t = FramingMode::Chunked, help = "How messages are framed on the wire")]
+    framing: FramingMode,
+
+    #[arg(long, value_enum, default_value_t = Codec::Bincode, conflicts_with = "udp", help = "How messages are serialized")]
+    codec: Codec,
+
+    #[arg(long, help = "Ask for a CRC32C trailer on every frame")]
+    checksum: bool,
+
+    #[arg(long, default_value_t = 1, help = "Requests each closed-loop connection keeps in flight")]
+    pipeline_depth: usize,
+
+    #[arg(long, default_value_t = 1, help = "Requests sent together in one frame")]
+    batch_size: usize,
+
+    #[arg(long, default_value_t = 500, help = "Ping the server after this many milliseconds without hearing from it")]
+    heartbeat_ms: u64,
+
+    #[arg(long, default_value_t = 5000, help = "Give up on a server that has been silent this many milliseconds")]
+    dead_after_ms: u64,
+
+    #[command(flatten)]
+    socket: SocketOptions,
+
+    #[cfg(feature = "tls")]
+    #[arg(long, conflicts_with_all = ["unix", "shm", "udp"], help = "Use TLS, trusting the certificates in this PEM file")]
+    tls_ca: Option<PathBuf>,
+
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_ca", help = "Name the server's certificate must match [default: --ip]")]
+    tls_server_name: Option<String>,
 }
 
 fn main() {
     let opt = Opt::parse();
-    let server_addr = SocketAddrV4::new(opt.ip, opt.port);
+    let server_addr = match (opt.unix, opt.shm, &opt.ip, opt.port) {
+        (Some(path), _, _, _) => Endpoint::Unix(path),
+        (None, Some(path), _, _) => Endpoint::Shm(path),
+        (None, None, Some(host), Some(port)) => Endpoint::resolve(host, port)
+            .unwrap_or_else(|e| panic!("can't resolve {}: {}", host, e)),
+        _ => unreachable!("clap requires --ip and --port, --unix or --shm"),
+    };
     let runtime = Duration::from_secs(opt.runtime_secs);
     let outpath = opt.outpath.clone();
-    if let Some(interarrival) = opt.interval_us {
+    let config = ClientConfig {
+        params: ConnParams {
+            framing: opt.framing,
+            codec: opt.codec,
+            features: if opt.checksum {
+                Features::CHECKSUM
+            } else {
+                Features::NONE
+            },
+        },
+        pipeline_depth: opt.pipeline_depth,
+        batch_size: opt.batch_size.max(1),
+        heartbeat_interval: Duration::from_millis(opt.heartbeat_ms.max(1)),
+        dead_after: Duration::from_millis(opt.dead_after_ms),
+        response_timeout: Duration::from_millis(opt.response_timeout_ms.max(1)),
+        socket: opt.socket.clone(),
+        #[cfg(feature = "tls")]
+        tls: opt.tls_ca.as_ref().map(|ca| {
+            let name = opt.tls_server_name.as_ref().or(opt.ip.as_ref()).unwrap();
+            woonsocket::tls::TlsClient::from_pem(ca, name)
+                .unwrap_or_else(|e| panic!("can't load TLS trust anchors: {}", e))
+        }),
+    };
+    if let (true, Some(interarrival), Endpoint::Tcp(addr)) = (opt.udp, opt.interval_us, &server_addr) {
+        udp_client::run(
+            *addr,
+            opt.num_threads as _,
+            Duration::from_micros(interarrival),
+            runtime,
+            opt.work,
+            outpath,
+            config,
+        );
+    } else if let Some(interarrival) = opt.interval_us {
         open_loop_client::run(
             server_addr,
             opt.num_threads as _,
@@ -44,6 +126,7 @@ fn main() {
             runtime,
             opt.work,
             outpath,
+            config,
         );
     } else {
         closed_loop_client::run(
@@ -52,6 +135,7 @@ fn main() {
             runtime,
             opt.work,
             outpath,
+            config,
         );
     }
 }
@@ -1,10 +1,15 @@
 //! Server logic for the CS1675 Woonsocket project.
 
-use clap::{Parser, ValueEnum};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 use woonsocket::{
-    io_uring_server::io_uring_server, io_vec_server::io_vec_server, tcp_server::tcp_server,
+    config::ServerConfig, io_uring::RingSetup, io_uring_server::io_uring_server, io_vec_server::io_vec_server,
+    protocol::DEFAULT_MAX_FRAME_LEN, shutdown::Shutdown,
+    sockopt::{SocketOptions, DEFAULT_BACKLOG},
+    stats::ServerStats,
+    tcp_server::tcp_server, transport::Endpoint, udp_server::udp_server,
 };
 
 #[derive(Copy, Clone, Debug, ValueEnum, Eq, PartialEq)]
@@ -14,6 +19,7 @@ pub enum ServerKind {
     tcp,
     iouring_0,
     io_vec,
+    udp,
 }
 
 impl ServerKind {
@@ -22,34 +28,143 @@ impl ServerKind {
             Self::tcp => "tcp",
             Self::iouring_0 => "iouring-0",
             Self::io_vec => "io-vec",
+            Self::udp => "udp",
         }
         .into()
     }
 }
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about=None)]
+#[command(name = "server", version, about, long_about=None)]
 struct Args {
-    #[arg(short, long)]
-    port: u16,
+    #[arg(short, long, required_unless_present_any = ["unix", "shm"])]
+    port: Option<u16>,
+
+    #[arg(long, default_value = "0.0.0.0", conflicts_with_all = ["unix", "shm"], help = "Address or host name to listen on, IPv4 or IPv6")]
+    bind: String,
+
+    #[arg(long, conflicts_with = "port", help = "Listen on a Unix domain socket at this path instead of TCP")]
+    unix: Option<PathBuf>,
+
+    #[arg(long, conflicts_with_all = ["port", "unix"], help = "Take shared-memory clients (tcp kind only) on a Unix domain socket at this path")]
+    shm: Option<PathBuf>,
 
     #[arg(short, long)]
     kind: ServerKind,
 
-    #[arg(long)]
-    ring_sz: Option<usize>,
+    #[command(flatten)]
+    ring: RingSetup,
+
+    #[arg(long, help = "Register buffers and connection descriptors with the io_uring (iouring-0 only)")]
+    ring_fixed: bool,
+
+    #[arg(long, value_name = "N", help = "Receive into N buffers shared by every connection, picked by the kernel (iouring-0 only; a power of two)")]
+    ring_provided_bufs: Option<u16>,
 
     #[arg(long)]
     runtime_secs: u64,
+
+    #[arg(long, default_value_t = DEFAULT_MAX_FRAME_LEN, help = "Largest request payload accepted, in bytes")]
+    max_frame_len: usize,
+
+    #[arg(long, default_value_t = 1, help = "Worker threads per connection (tcp only); more than one answers requests out of order")]
+    conn_workers: usize,
+
+    #[arg(long, help = "Reply to each request in a batch separately instead of with one batched frame")]
+    split_batches: bool,
+
+    #[arg(long, default_value_t = 10_000, help = "Close connections that stay silent this many milliseconds (0 never does)")]
+    idle_timeout_ms: u64,
+
+    #[arg(long, default_value_t = 1024, help = "Reject batches with more requests than this")]
+    max_batch_len: usize,
+
+    #[arg(long, help = "Fail requests not finished this many microseconds after they arrive")]
+    request_timeout_us: Option<u64>,
+
+    #[arg(long, default_value_t = DEFAULT_BACKLOG, help = "Listen backlog")]
+    backlog: u32,
+
+    #[command(flatten)]
+    socket: SocketOptions,
+
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_key", conflicts_with_all = ["unix", "shm"], help = "Serve TLS (tcp only) with the certificate chain in this PEM file")]
+    tls_cert: Option<PathBuf>,
+
+    #[cfg(feature = "tls")]
+    #[arg(long, requires = "tls_cert", help = "PEM file with the private key for --tls-cert")]
+    tls_key: Option<PathBuf>,
+}
+
+/// Reject flags the chosen server kind can't honour, the way clap rejects bad usage.
+fn check_kind(args: &Args) {
+    let conflict = |msg: &str| Args::command().error(ErrorKind::ArgumentConflict, msg).exit();
+    if args.shm.is_some() && args.kind != ServerKind::tcp {
+        conflict("--shm needs --kind tcp: only the tcp server speaks shared memory");
+    }
+    #[cfg(feature = "tls")]
+    if args.tls_cert.is_some() && args.kind != ServerKind::tcp {
+        conflict("--tls-cert needs --kind tcp: only the tcp server speaks TLS");
+    }
+    if args.kind == ServerKind::udp && args.port.is_none() {
+        conflict("--kind udp needs --port");
+    }
 }
 
 fn main() {
     let args = Args::parse();
-    let addr = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, args.port);
-    std::thread::spawn(move || match args.kind {
-        ServerKind::tcp => tcp_server(addr),
-        ServerKind::io_vec => io_vec_server(addr),
-        ServerKind::iouring_0 => io_uring_server(addr, args.ring_sz.unwrap()),
-    });
+    check_kind(&args);
+    let endpoint = match (args.unix, args.shm, args.port) {
+        (Some(path), _, _) => Endpoint::Unix(path),
+        (None, Some(path), _) => Endpoint::Shm(path),
+        (None, None, Some(port)) => Endpoint::resolve(&args.bind, port)
+            .unwrap_or_else(|e| panic!("can't listen on {}: {}", args.bind, e)),
+        (None, None, None) => unreachable!("clap requires --port, --unix or --shm"),
+    };
+    let config = ServerConfig {
+        max_frame_len: args.max_frame_len,
+        conn_workers: args.conn_workers,
+        split_batches: args.split_batches,
+        idle_timeout: (args.idle_timeout_ms > 0).then(|| Duration::from_millis(args.idle_timeout_ms)),
+        max_batch_len: args.max_batch_len,
+        request_timeout: args.request_timeout_us.map(Duration::from_micros),
+        socket: SocketOptions {
+            backlog: args.backlog,
+            ..args.socket.clone()
+        },
+        #[cfg(feature = "tls")]
+        tls: match (&args.tls_cert, &args.tls_key) {
+            (Some(cert), Some(key)) => Some(
+                woonsocket::tls::server_config_from_pem(cert, key)
+                    .unwrap_or_else(|e| panic!("can't load TLS certificate: {}", e)),
+            ),
+            _ => None,
+        },
+        ..Default::default()
+    };
+    let stats = Arc::new(ServerStats::default());
+    let shutdown = Arc::new(Shutdown::default());
+    {
+        let stats = stats.clone();
+        let shutdown = shutdown.clone();
+        std::thread::spawn(move || match args.kind {
+            ServerKind::tcp => tcp_server(endpoint, config, stats, shutdown),
+            ServerKind::io_vec => io_vec_server(endpoint, config, stats, shutdown),
+            ServerKind::iouring_0 => io_uring_server(endpoint, config, stats, shutdown, args.ring.clone(), args.ring_fixed, args.ring_provided_bufs),
+            ServerKind::udp => match endpoint {
+                Endpoint::Tcp(addr) => udp_server(addr, config, stats, shutdown),
+                Endpoint::Unix(_) | Endpoint::Shm(_) => unreachable!("checked by check_kind"),
+            },
+        });
+    }
     std::thread::sleep(Duration::from_secs(args.runtime_secs));
+
+    // Tell connected clients we're going, and give them a moment to hear it.
+    shutdown.begin();
+    let open = shutdown.wait_closed(Duration::from_secs(1));
+    if open > 0 {
+        eprintln!("{} connections still open at exit", open);
+    }
+    println!("server stats: {}", stats);
 }
@@ -1,43 +1,337 @@
+use crate::transport::{Stream, Transport};
 use libc::{self, iovec};
 use std::{
-    io::{Read, Write},
-    net::TcpStream,
+    io::{self, IoSlice, IoSliceMut},
     os::fd::RawFd,
+    slice,
 };
 
 pub const MSG_SIZE_BYTES: usize = 128;
 
-pub struct ChunkedTcpStream(TcpStream);
+/// Most iovecs one `writev` or `readv` takes on Linux (`UIO_MAXIOV`).
+pub const IOV_MAX: usize = 1024;
 
-impl ChunkedTcpStream {
-    pub fn send_msg_chunk(&mut self, bytes: &[u8]) -> Result<(), anyhow::Error> {
+/// Moves bytes in chunks of at most [`MSG_SIZE_BYTES`] over any [`Transport`]; by default a
+/// TCP or Unix stream socket.
+pub struct ChunkedTcpStream<S = Stream>(S);
+
+impl<S: Transport> ChunkedTcpStream<S> {
+    pub fn send_msg_chunk(&mut self, bytes: &[u8]) -> io::Result<()> {
         assert!(bytes.len() <= MSG_SIZE_BYTES);
-        self.0.write_all(&bytes)?;
+        self.0.write_all(bytes)?;
         self.0.flush()?;
         Ok(())
     }
 
-    pub fn recv_msg_chunk(&mut self, bytes: &mut [u8]) -> Result<(), anyhow::Error> {
+    /// Send `chunks` back to back with as few vectored writes as the stream allows. A short
+    /// write, or one interrupted by a signal, resumes where it stopped.
+    pub fn send_msg_chunks(&mut self, chunks: &[&[u8]]) -> io::Result<()> {
+        assert!(chunks.iter().all(|chunk| chunk.len() <= MSG_SIZE_BYTES));
+        let mut slices: Vec<IoSlice<'_>> = chunks.iter().map(|chunk| IoSlice::new(chunk)).collect();
+        let mut remaining = &mut slices[..];
+        // Skips leading empty chunks, so an empty `remaining` means everything was sent.
+        IoSlice::advance_slices(&mut remaining, 0);
+        while !remaining.is_empty() {
+            let count = remaining.len().min(IOV_MAX);
+            match self.0.write_vectored(&remaining[..count]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => IoSlice::advance_slices(&mut remaining, n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.0.flush()
+    }
+
+    pub fn recv_msg_chunk(&mut self, bytes: &mut [u8]) -> io::Result<()> {
         assert!(bytes.len() <= MSG_SIZE_BYTES);
         self.0.read_exact(bytes)?;
         Ok(())
     }
 
-    pub fn new(tcp: TcpStream) -> Self {
-        Self(tcp)
+    /// Read at least `at_least` and at most `at_most` bytes into `chunks`, with as few
+    /// vectored reads as the stream allows. Returns how many bytes arrived, fewer than
+    /// `at_least` only at end of stream or when a read failed. The bytes that did arrive
+    /// are in `chunks` even then, so a caller retrying after a timeout must keep them.
+    pub fn recv_msg_chunks(
+        &mut self,
+        chunks: &mut RecvChunks,
+        at_least: usize,
+        at_most: usize,
+    ) -> (usize, io::Result<()>) {
+        chunks.fill(at_least, at_most, |slices| self.0.read_vectored(slices))
+    }
+
+    pub fn new(stream: S) -> Self {
+        Self(stream)
+    }
+
+    /// Another handle to the same underlying stream.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self(self.0.try_clone()?))
     }
 }
 
+/// # Safety
+///
+/// Every `iovec` in `iovecs[..send_idx]` must point to memory valid for reads of `iov_len` bytes.
 pub unsafe fn writev(raw_fd: RawFd, iovecs: &mut [iovec], send_idx: i32) -> isize {
     for v in iovecs.iter() {
-        assert!(v.iov_len as usize <= MSG_SIZE_BYTES);
+        assert!(v.iov_len <= MSG_SIZE_BYTES);
     }
     libc::writev(raw_fd, iovecs.as_mut_ptr(), send_idx)
 }
 
+/// Chunk-sized receive buffers, allocated once and reused for every read. They sit back to
+/// back, so what a read fills is one contiguous slice.
+pub struct RecvChunks(Box<[[u8; MSG_SIZE_BYTES]]>);
+
+impl RecvChunks {
+    /// Room for `count` chunks, at least one and at most [`IOV_MAX`].
+    pub fn new(count: usize) -> Self {
+        assert!(count <= IOV_MAX);
+        Self(vec![[0; MSG_SIZE_BYTES]; count.max(1)].into_boxed_slice())
+    }
+
+    /// Bytes the buffers hold.
+    pub fn capacity(&self) -> usize {
+        self.0.len() * MSG_SIZE_BYTES
+    }
+
+    /// The first `len` bytes, as the last read left them.
+    pub fn filled(&self, len: usize) -> &[u8] {
+        &self.0.as_flattened()[..len]
+    }
+
+    /// Like [`ChunkedTcpStream::recv_msg_chunks`], but calling [`readv`] on `raw_fd`.
+    pub fn readv_from(&mut self, raw_fd: RawFd, at_least: usize, at_most: usize) -> (usize, io::Result<()>) {
+        self.fill(at_least, at_most, |slices| {
+            // SAFETY: `IoSliceMut` is ABI compatible with `iovec` on Unix, and every slice
+            // points into our own buffers.
+            let n = unsafe {
+                let iovecs = slice::from_raw_parts_mut(slices.as_mut_ptr().cast(), slices.len());
+                readv(raw_fd, iovecs, slices.len() as i32)
+            };
+            if n < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(n as usize)
+            }
+        })
+    }
+
+    /// Call `read` until at least `at_least` bytes have arrived or it reports end of stream,
+    /// resuming after short reads and interruptions. Any other error stops it, but the
+    /// bytes before it are still counted.
+    fn fill(
+        &mut self,
+        at_least: usize,
+        at_most: usize,
+        mut read: impl FnMut(&mut [IoSliceMut<'_>]) -> io::Result<usize>,
+    ) -> (usize, io::Result<()>) {
+        assert!(at_least <= at_most && at_most <= self.capacity());
+        let mut slices: Vec<IoSliceMut<'_>> = self.0.as_flattened_mut()[..at_most]
+            .chunks_mut(MSG_SIZE_BYTES)
+            .map(IoSliceMut::new)
+            .collect();
+        let mut remaining = &mut slices[..];
+        let mut received = 0;
+        while received < at_least {
+            match read(remaining) {
+                Ok(0) => break,
+                Ok(n) => {
+                    received += n;
+                    IoSliceMut::advance_slices(&mut remaining, n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return (received, Err(e)),
+            }
+        }
+        (received, Ok(()))
+    }
+}
+
+/// Write everything `iovecs` points to, calling [`writev`] again after short writes and
+/// interruptions, with at most [`IOV_MAX`] iovecs a call. `iovecs` is used up as it goes.
+///
+/// # Safety
+///
+/// Every `iovec` in `iovecs` must point to memory valid for reads of `iov_len` bytes.
+pub unsafe fn writev_all(raw_fd: RawFd, mut iovecs: &mut [iovec]) -> io::Result<()> {
+    loop {
+        while iovecs.first().is_some_and(|v| v.iov_len == 0) {
+            iovecs = &mut iovecs[1..];
+        }
+        if iovecs.is_empty() {
+            return Ok(());
+        }
+        let count = iovecs.len().min(IOV_MAX);
+        let n = writev(raw_fd, &mut iovecs[..count], count as i32);
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        iovecs = advance_iovecs(iovecs, n as usize);
+    }
+}
+
+/// Drop the first `n` bytes from `iovecs`, leaving the first iovec that isn't used up
+/// pointing at its rest.
+fn advance_iovecs(iovecs: &mut [iovec], mut n: usize) -> &mut [iovec] {
+    let mut done = 0;
+    while done < iovecs.len() && n >= iovecs[done].iov_len {
+        n -= iovecs[done].iov_len;
+        done += 1;
+    }
+    let rest = &mut iovecs[done..];
+    if let Some(first) = rest.first_mut() {
+        // SAFETY: `n` is less than `first.iov_len`, so this stays inside the same buffer.
+        first.iov_base = unsafe { first.iov_base.cast::<u8>().add(n) }.cast();
+        first.iov_len -= n;
+    }
+    rest
+}
+
+/// # Safety
+///
+/// Every `iovec` in `iovecs[..send_idx]` must point to memory valid for writes of `iov_len` bytes.
 pub unsafe fn readv(raw_fd: RawFd, iovecs: &mut [iovec], send_idx: i32) -> isize {
     for v in iovecs.iter() {
-        assert!(v.iov_len as usize <= MSG_SIZE_BYTES);
+        assert!(v.iov_len <= MSG_SIZE_BYTES);
     }
     libc::readv(raw_fd, iovecs.as_mut_ptr(), send_idx)
 }
+
+/// A connected in-memory pair, `(client, server)`, for tests.
+#[cfg(test)]
+pub(crate) fn stream_pair() -> (
+    ChunkedTcpStream<crate::transport::MemPipe>,
+    ChunkedTcpStream<crate::transport::MemPipe>,
+) {
+    let (client, server) = crate::transport::MemPipe::pair();
+    (ChunkedTcpStream::new(client), ChunkedTcpStream::new(server))
+}
+
+#[cfg(test)]
+mod t {
+    use super::{writev_all, ChunkedTcpStream, RecvChunks, IOV_MAX, MSG_SIZE_BYTES};
+    use crate::transport::Transport;
+    use libc::iovec;
+    use std::{
+        io::{self, IoSlice, Read, Write},
+        os::{fd::AsRawFd, unix::net::UnixStream},
+        thread,
+        time::Duration,
+    };
+
+    /// Takes at most a few bytes a write, and fails every other write with `EINTR`.
+    #[derive(Default)]
+    struct Stingy {
+        written: Vec<u8>,
+        calls: usize,
+    }
+
+    impl Read for Stingy {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for Stingy {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.calls += 1;
+            if self.calls.is_multiple_of(2) {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let mut budget = 100;
+            for buf in bufs {
+                let n = buf.len().min(budget);
+                self.written.extend_from_slice(&buf[..n]);
+                budget -= n;
+            }
+            Ok(100 - budget)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Stingy {
+        fn try_clone(&self) -> io::Result<Self> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "test stream can't be cloned"))
+        }
+    }
+
+    #[test]
+    fn send_msg_chunks_resumes_short_writes() {
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let chunks: Vec<&[u8]> = data.chunks(MSG_SIZE_BYTES).collect();
+        let mut stream = ChunkedTcpStream::new(Stingy::default());
+        stream.send_msg_chunks(&chunks).unwrap();
+        assert_eq!(stream.0.written, data);
+    }
+
+    #[test]
+    fn writev_all_past_iov_max() {
+        // Chunks of every length up to the limit, empty ones included, more than one call takes.
+        let data: Vec<u8> = (0..IOV_MAX * MSG_SIZE_BYTES).map(|i| (i * 7) as u8).collect();
+        let mut iovecs = Vec::new();
+        let mut rest = &data[..];
+        for len in (0..=MSG_SIZE_BYTES).cycle().take(IOV_MAX + 100) {
+            let (chunk, tail) = rest.split_at(len.min(rest.len()));
+            iovecs.push(iovec {
+                iov_base: chunk.as_ptr() as *mut _,
+                iov_len: chunk.len(),
+            });
+            rest = tail;
+        }
+        let sent = data.len() - rest.len();
+
+        let (mut reader, writer) = UnixStream::pair().unwrap();
+        let received = thread::spawn(move || {
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).unwrap();
+            received
+        });
+        // SAFETY: every iovec points into `data`, which outlives the call.
+        unsafe { writev_all(writer.as_raw_fd(), &mut iovecs).unwrap() };
+        drop(writer);
+        assert_eq!(received.join().unwrap(), data[..sent]);
+    }
+
+    #[test]
+    fn readv_from_resumes_short_reads() {
+        let data: Vec<u8> = (0..500u32).map(|i| (i * 3) as u8).collect();
+        let (reader, mut writer) = UnixStream::pair().unwrap();
+        let sent = data.clone();
+        let writer = thread::spawn(move || {
+            writer.write_all(&sent[..100]).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            writer.write_all(&sent[100..]).unwrap();
+        });
+
+        let mut chunks = RecvChunks::new(4);
+        let (n, result) = chunks.readv_from(reader.as_raw_fd(), 300, 400);
+        result.unwrap();
+        assert!((300..=400).contains(&n), "{}", n);
+        assert_eq!(chunks.filled(n), &data[..n]);
+        writer.join().unwrap();
+
+        // At end of stream it returns what there was.
+        let (m, result) = chunks.readv_from(reader.as_raw_fd(), 500 - n, 500 - n);
+        result.unwrap();
+        assert_eq!(chunks.filled(m), &data[n..]);
+        assert_eq!(chunks.readv_from(reader.as_raw_fd(), 1, 1).0, 0);
+    }
+}
@@ -1,15 +1,21 @@
 use crate::{
     app::Work,
     chunked_tcp_stream::ChunkedTcpStream,
-    protocol::work_request::ClientWorkPacketConn,
-    protocol::work_response::ServerWorkPacketConn,
-    serialize::{ClientWorkPacket, LatencyRecord},
+    config::ClientConfig,
+    handshake,
+    pipeline::{PipelineError, PipelinedConn},
+    protocol::{FramedReceiver, FramedSender, ProtocolError},
+    serialize::{
+        ClientMessage, ClientWorkPacket, LatencyRecord, ServerMessage, UNKNOWN_REQUEST_ID,
+    },
+    stats::ClientStats,
+    transport::{Endpoint, Stream},
     get_current_time_micros,
 };
 
 use std::{
-    net::{SocketAddrV4, TcpStream},
     path::PathBuf,
+    sync::Arc,
     thread::{self, JoinHandle},
     time::Duration,
 };
@@ -17,47 +23,140 @@ use std::{
 use csv::Writer;
 use minstant::Instant;
 
-fn client_worker(server_addr: SocketAddrV4, runtime: Duration, work: Work) -> Vec<LatencyRecord> {
-    let stream = TcpStream::connect(server_addr).unwrap();
+fn client_worker(
+    server_addr: Endpoint,
+    runtime: Duration,
+    work: Work,
+    config: ClientConfig,
+    stats: Arc<ClientStats>,
+) -> Vec<LatencyRecord> {
+    let stream = Stream::connect(&server_addr).unwrap();
+    config
+        .socket
+        .apply(&stream)
+        .expect("Failed to set socket options")
+        .log_once("client");
+    // Wake up regularly while waiting, to ping the server and notice if it has died.
+    stream
+        .set_read_timeout(Some(config.heartbeat_interval))
+        .expect("Failed to set read timeout");
+    let handshake_start = Instant::now();
+    #[cfg(feature = "tls")]
+    let stream = match &config.tls {
+        Some(tls) => crate::tls::connect(stream, tls)
+            .unwrap_or_else(|e| panic!("TLS handshake with {} failed: {}", server_addr, e)),
+        None => stream,
+    };
+    let mut stream = ChunkedTcpStream::new(stream);
+    let params = handshake::connect(&mut stream, config.params)
+        .unwrap_or_else(|e| panic!("handshake with {} failed: {}", server_addr, e));
+    stats.record_handshake(handshake_start.elapsed());
     let clone_stream = stream.try_clone().unwrap();
-    let mut client_conn = ClientWorkPacketConn::new(ChunkedTcpStream::new(clone_stream));
-    let mut server_conn = ServerWorkPacketConn::new(ChunkedTcpStream::new(stream));
+    let client_conn = FramedSender::<ClientMessage>::new(clone_stream).with_params(params);
+    let server_conn = FramedReceiver::<ServerMessage>::new(stream).with_params(params);
+    let mut conn = PipelinedConn::new(client_conn, server_conn);
 
     let mut latencies: Vec<LatencyRecord> = Vec::new();
     let mut id: u64 = 0;
+    // Responses that arrived corrupted. We can't tell which request they answered, so
+    // those requests stay outstanding forever and the window is widened to make up. A
+    // corrupted frame may have been a pong or goodbye instead, so this never exceeds the
+    // requests actually in flight.
+    let mut lost = 0;
+    // Only whole batches are sent, so the window must fit at least one.
+    let window = config.pipeline_depth.max(config.batch_size);
+    // Set after our goodbye, or once a send fails. A server that is shutting down stops
+    // reading but still answers what it got and says goodbye, so either way receiving
+    // carries on until the connection ends.
+    let mut done_sending = false;
     let start = Instant::now();
 
-    while start.elapsed() < runtime {
-        let work_packet = ClientWorkPacket::new(id, work);
-        client_conn.send_work_msg(work_packet).unwrap();
-
-        let msg = server_conn.recv_work_msg().unwrap();
-        let lat = msg.calculate_latency(get_current_time_micros()).unwrap();
-        latencies.push(lat);
+    // Why the connection ended, unless the server said goodbye or went silent.
+    let failure = loop {
+        if !done_sending && start.elapsed() >= runtime {
+            // The server answers what's in flight, then says goodbye back.
+            let _ = conn.goodbye();
+            done_sending = true;
+        }
+        while !done_sending && conn.in_flight() + config.batch_size <= window + lost {
+            let sent = if config.batch_size > 1 {
+                let batch = (id..id + config.batch_size as u64)
+                    .map(|id| ClientWorkPacket::new(id, work))
+                    .collect();
+                conn.send_batch(batch)
+            } else {
+                conn.send(&ClientWorkPacket::new(id, work))
+            };
+            done_sending = sent.is_err();
+            id += config.batch_size as u64;
+        }
 
-        id += 1;
-    }
+        match conn.recv() {
+            Ok(Some(msg)) => {
+                lost = lost.min(conn.in_flight());
+                if stats.record_failure(&msg) {
+                    // A failure we can't match leaves its request in flight for good.
+                    if msg.client_id() == UNKNOWN_REQUEST_ID && conn.in_flight() > lost {
+                        lost += 1;
+                    }
+                } else if let Some(lat) = msg.calculate_latency(get_current_time_micros()) {
+                    latencies.push(lat);
+                }
+            }
+            Ok(None) => {
+                stats.record_unanswered(&server_addr, (conn.in_flight() - lost) as u64);
+                return latencies;
+            }
+            Err(PipelineError::Protocol(e @ ProtocolError::Checksum { .. })) => {
+                stats.record_checksum_failure(&e);
+                if conn.in_flight() > lost {
+                    lost += 1;
+                }
+            }
+            Err(
+                e @ (PipelineError::DuplicateResponse(_) | PipelineError::UnknownResponse(_)),
+            ) => stats.record_unmatched_response(&e),
+            Err(PipelineError::Protocol(e)) if e.is_timeout() => {
+                if conn.silent_for() >= config.dead_after {
+                    stats.record_dead_server(&server_addr, conn.silent_for());
+                    stats.record_unanswered(&server_addr, (conn.in_flight() - lost) as u64);
+                    return latencies;
+                }
+                if !done_sending {
+                    done_sending = conn.ping().is_err();
+                }
+            }
+            Err(e) => break e,
+        }
+    };
 
+    // The run goes on without this connection, keeping what it measured.
+    stats.record_close(&server_addr, &failure);
+    stats.record_unanswered(&server_addr, (conn.in_flight() - lost) as u64);
     latencies
 }
 
 pub fn init_client(
-    server_addr: SocketAddrV4,
+    server_addr: Endpoint,
     runtime: Duration,
     work: Work,
+    config: ClientConfig,
+    stats: Arc<ClientStats>,
 ) -> JoinHandle<Vec<LatencyRecord>> {
-    thread::spawn(move || client_worker(server_addr, runtime, work))
+    thread::spawn(move || client_worker(server_addr, runtime, work, config, stats))
 }
 
 pub fn run(
-    server_addr: SocketAddrV4,
+    server_addr: Endpoint,
     num_threads: usize,
     runtime: Duration,
     work: Work,
     outdir: PathBuf,
+    config: ClientConfig,
 ) {
+    let stats = Arc::new(ClientStats::default());
     let join_handles: Vec<_> = (0..num_threads)
-        .map(|_| init_client(server_addr, runtime, work))
+        .map(|_| init_client(server_addr.clone(), runtime, work, config.clone(), stats.clone()))
         .collect();
 
     // Collect latencies
@@ -66,10 +165,11 @@ pub fn run(
         let thread_latencies = handle.join().unwrap();
         request_latencies.push(thread_latencies);
     }
+    println!("client stats: {}", stats);
 
     let path = outdir.join("closed_loop_latencies.csv");
     let mut w = Writer::from_path(&path).unwrap();
-    w.write_record(&["idx", "send_us", "recv_us", "server_proc_us", "latency_us"]).unwrap();
+    w.write_record(["idx", "send_us", "recv_us", "server_proc_us", "latency_us"]).unwrap();
 
     for thread_recs in request_latencies {
         for (i, rec) in thread_recs.iter().enumerate() {
@@ -83,3 +183,171 @@ pub fn run(
     }
     w.flush().unwrap();
 }
+
+#[cfg(test)]
+mod t {
+    use super::init_client;
+    use crate::{
+        app::Work,
+        chunked_tcp_stream::ChunkedTcpStream,
+        config::ClientConfig,
+        handshake::{self, Capabilities, ConnParams, Features},
+        protocol::{encode_frame, FramedReceiver, FramedSender, MSG_SIZE_BYTES},
+        serialize::{ClientMessage, ServerMessage},
+        stats::ClientStats,
+        transport::{Endpoint, Stream},
+    };
+    use std::{
+        io::Write,
+        net::TcpListener,
+        sync::{atomic::Ordering, Arc},
+        thread,
+        time::Duration,
+    };
+
+    /// Hold back the reply to the first request until the client pings, answer that ping
+    /// with a corrupted pong, then serve normally.
+    fn corrupt_first_pong(listener: TcpListener) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut raw = stream.try_clone().unwrap();
+        let mut stream = ChunkedTcpStream::new(Stream::Tcp(stream));
+        let params = handshake::accept(&mut stream, &Capabilities::default()).unwrap();
+        let mut rx = FramedReceiver::<ClientMessage>::new(stream.try_clone().unwrap()).with_params(params);
+        let mut tx = FramedSender::<ServerMessage>::new(stream).with_params(params);
+
+        let mut held = None;
+        let mut corrupted = false;
+        loop {
+            match rx.recv_msg().unwrap() {
+                ClientMessage::Work(packet) if !corrupted => held = Some(packet.do_work()),
+                ClientMessage::Work(packet) => tx.send_msg(&ServerMessage::Work(packet.do_work())).unwrap(),
+                ClientMessage::Ping(token) if !corrupted => {
+                    let mut frame = Vec::new();
+                    encode_frame(&ServerMessage::Pong(token), &params, &mut frame).unwrap();
+                    // The first payload byte, just past the header chunk.
+                    frame[MSG_SIZE_BYTES] ^= 0xff;
+                    raw.write_all(&frame).unwrap();
+                    corrupted = true;
+                    tx.send_msg(&ServerMessage::Work(held.take().unwrap())).unwrap();
+                }
+                ClientMessage::Ping(token) => tx.send_msg(&ServerMessage::Pong(token)).unwrap(),
+                ClientMessage::Goodbye => {
+                    tx.send_msg(&ServerMessage::Goodbye).unwrap();
+                    return;
+                }
+                other => panic!("unexpected request {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted_pong_does_not_count_as_a_lost_reply() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = Endpoint::Tcp(listener.local_addr().unwrap());
+        let server = thread::spawn(move || corrupt_first_pong(listener));
+
+        let config = ClientConfig {
+            params: ConnParams {
+                features: Features::CHECKSUM,
+                ..Default::default()
+            },
+            pipeline_depth: 1,
+            batch_size: 1,
+            heartbeat_interval: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let stats = Arc::new(ClientStats::default());
+        let client = init_client(endpoint, Duration::from_millis(100), Work::Immediate, config, stats.clone());
+        let latencies = client.join().unwrap();
+        server.join().unwrap();
+
+        assert!(!latencies.is_empty());
+        assert_eq!(stats.checksum_failures.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.unanswered_requests.load(Ordering::Relaxed), 0);
+    }
+
+    /// Answer `replies` requests and take in `unanswered` more, then drop the connection
+    /// without a goodbye. Nothing is left unread, so the client sees an end of stream
+    /// rather than a reset.
+    fn hang_up_after(listener: TcpListener, replies: usize, unanswered: usize) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = ChunkedTcpStream::new(Stream::Tcp(stream));
+        let params = handshake::accept(&mut stream, &Capabilities::default()).unwrap();
+        let mut rx = FramedReceiver::<ClientMessage>::new(stream.try_clone().unwrap()).with_params(params);
+        let mut tx = FramedSender::<ServerMessage>::new(stream).with_params(params);
+        let mut received = 0;
+        while received < replies + unanswered {
+            if let ClientMessage::Work(packet) = rx.recv_msg().unwrap() {
+                if received < replies {
+                    tx.send_msg(&ServerMessage::Work(packet.do_work())).unwrap();
+                }
+                received += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn server_hanging_up_ends_the_connection_not_the_run() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = Endpoint::Tcp(listener.local_addr().unwrap());
+        let server = thread::spawn(move || hang_up_after(listener, 10, 4));
+
+        let config = ClientConfig {
+            pipeline_depth: 4,
+            batch_size: 1,
+            ..Default::default()
+        };
+        let stats = Arc::new(ClientStats::default());
+        // Far longer than the test may take: only the hang-up can end it.
+        let client = init_client(endpoint, Duration::from_secs(60), Work::Immediate, config, stats.clone());
+        let latencies = client.join().unwrap();
+        server.join().unwrap();
+
+        assert_eq!(latencies.len(), 10);
+        assert_eq!(stats.closed_connections.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.unanswered_requests.load(Ordering::Relaxed), 4);
+    }
+
+    /// Act like a server shutting down once `requests` have arrived: answer them, say
+    /// goodbye unprompted and close, while the client is still sending. Everything goes
+    /// out in one write, so closing can't cut any of it off.
+    fn goodbye_after(listener: TcpListener, requests: usize) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut raw = stream.try_clone().unwrap();
+        let mut stream = ChunkedTcpStream::new(Stream::Tcp(stream));
+        let params = handshake::accept(&mut stream, &Capabilities::default()).unwrap();
+        let mut rx = FramedReceiver::<ClientMessage>::new(stream).with_params(params);
+        let mut out = Vec::new();
+        let mut received = 0;
+        while received < requests {
+            if let ClientMessage::Work(packet) = rx.recv_msg().unwrap() {
+                encode_frame(&ServerMessage::Work(packet.do_work()), &params, &mut out).unwrap();
+                received += 1;
+            }
+        }
+        encode_frame(&ServerMessage::Goodbye, &params, &mut out).unwrap();
+        raw.write_all(&out).unwrap();
+    }
+
+    #[test]
+    fn replies_before_an_unprompted_goodbye_still_count() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = Endpoint::Tcp(listener.local_addr().unwrap());
+        let server = thread::spawn(move || goodbye_after(listener, 16));
+
+        let config = ClientConfig {
+            pipeline_depth: 16,
+            batch_size: 1,
+            ..Default::default()
+        };
+        let stats = Arc::new(ClientStats::default());
+        let client = init_client(endpoint, Duration::from_secs(60), Work::Immediate, config, stats.clone());
+        let latencies = client.join().unwrap();
+        server.join().unwrap();
+
+        // Requests sent after the server closed fail, which mustn't lose the replies
+        // already on their way.
+        assert_eq!(latencies.len(), 16);
+        assert_eq!(stats.closed_connections.load(Ordering::Relaxed), 0);
+    }
+}
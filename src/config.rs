@@ -0,0 +1,88 @@
+//! Run-time configuration shared by the server kinds and by the load generators.
+
+use std::time::Duration;
+
+use crate::{
+    handshake::{Capabilities, ConnParams},
+    protocol::DEFAULT_MAX_FRAME_LEN,
+    sockopt::SocketOptions,
+};
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Largest request payload accepted, in bytes. Larger frames close the connection.
+    pub max_frame_len: usize,
+    /// What the server agrees to during the handshake.
+    pub capabilities: Capabilities,
+    /// Worker threads per connection for the `tcp` server. With more than one, requests on
+    /// a connection run concurrently and their responses may go out of order.
+    pub conn_workers: usize,
+    /// Answer each request in a batch with its own frame instead of one batched reply.
+    pub split_batches: bool,
+    /// Larger batches are refused, with a failed reply for each request.
+    pub max_batch_len: usize,
+    /// Requests not finished this long after they arrived get a failed reply instead.
+    pub request_timeout: Option<Duration>,
+    /// Close a connection after this long without hearing anything from the client.
+    /// `None` waits forever.
+    pub idle_timeout: Option<Duration>,
+    /// Set on the listening socket and on every accepted connection.
+    pub socket: SocketOptions,
+    /// Run TLS on every connection (`tcp` server only).
+    #[cfg(feature = "tls")]
+    pub tls: Option<std::sync::Arc<rustls::ServerConfig>>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            capabilities: Capabilities::default(),
+            conn_workers: 1,
+            split_batches: false,
+            max_batch_len: 1024,
+            request_timeout: None,
+            idle_timeout: Some(Duration::from_secs(10)),
+            socket: SocketOptions::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// What each connection asks for during the handshake.
+    pub params: ConnParams,
+    /// Requests the closed-loop client keeps in flight per connection.
+    pub pipeline_depth: usize,
+    /// Requests sent together in one frame. 1 sends each request on its own.
+    pub batch_size: usize,
+    /// How long to wait for the server before sending a ping.
+    pub heartbeat_interval: Duration,
+    /// Give up on a server that has been silent this long.
+    pub dead_after: Duration,
+    /// Over UDP, a request not answered this long after it was sent counts as lost.
+    pub response_timeout: Duration,
+    /// Set on every connection (or UDP socket) before anything is sent.
+    pub socket: SocketOptions,
+    /// Run TLS on every TCP connection.
+    #[cfg(feature = "tls")]
+    pub tls: Option<crate::tls::TlsClient>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            params: ConnParams::default(),
+            pipeline_depth: 1,
+            batch_size: 1,
+            heartbeat_interval: Duration::from_millis(500),
+            dead_after: Duration::from_secs(5),
+            response_timeout: Duration::from_secs(1),
+            socket: SocketOptions::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
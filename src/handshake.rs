@@ -0,0 +1,410 @@
+//! Connection handshake exchanged before the first [`ClientMessage`].
+//!
+//! The client opens with a [`ClientHello`] offering a protocol version and the
+//! [`ConnParams`] it wants to use. The server answers with a [`ServerHello`] that either
+//! accepts (with the parameters both sides will use from then on) or rejects the peer.
+//! Handshake messages themselves always use the default chunked framing and bincode, so
+//! that peers from different revisions can still understand each other's rejection.
+//!
+//! [`ClientMessage`]: crate::serialize::ClientMessage
+
+use crate::{
+    chunked_tcp_stream::{ChunkedTcpStream, RecvChunks},
+    protocol::{encode_frame, recv_frame, send_frame, FrameDecoder, ProtocolError, MSG_SIZE_BYTES},
+    serialize::MessageTrait,
+    transport::Transport,
+};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever the wire format changes incompatibly.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// Leads every [`ClientHello`] so that peers which skip the handshake are recognised.
+const HELLO_MAGIC: u32 = u32::from_be_bytes(*b"WOON");
+
+/// Handshake messages are tiny; anything bigger is not a handshake.
+const HELLO_MAX_FRAME_LEN: usize = MSG_SIZE_BYTES;
+
+/// Handshake messages always use the defaults, whatever the connection goes on to use.
+const HELLO_PARAMS: ConnParams = ConnParams {
+    framing: FramingMode::Chunked,
+    codec: Codec::Bincode,
+    features: Features::NONE,
+};
+
+/// How messages are delimited on the wire.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum FramingMode {
+    /// A header chunk with the payload length, then the payload in zero-padded
+    /// `MSG_SIZE_BYTES` chunks.
+    #[default]
+    Chunked,
+    /// A varint payload length, then exactly the payload.
+    Compact,
+}
+
+/// How messages are serialized. See [`MessageTrait::encode`].
+///
+/// [`MessageTrait::encode`]: crate::serialize::MessageTrait::encode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum Codec {
+    /// bincode 1, with its default options.
+    #[default]
+    Bincode,
+    /// JSON, readable in a packet capture.
+    Json,
+    /// A hand-written fixed layout of little-endian fields, read in place.
+    Fixed,
+}
+
+/// Optional protocol features, as a bit set. A feature is only used if both peers
+/// support it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Features(u32);
+
+impl Features {
+    pub const NONE: Self = Self(0);
+    /// Every frame carries a CRC32C trailer over its payload.
+    pub const CHECKSUM: Self = Self(1 << 0);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Per-connection settings agreed during the handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnParams {
+    pub framing: FramingMode,
+    pub codec: Codec,
+    pub features: Features,
+}
+
+/// What a server is willing to speak.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    pub framings: Vec<FramingMode>,
+    pub codecs: Vec<Codec>,
+    pub features: Features,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            framings: vec![FramingMode::Chunked, FramingMode::Compact],
+            codecs: vec![Codec::Bincode, Codec::Json, Codec::Fixed],
+            features: Features::CHECKSUM,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ClientHello {
+    magic: u32,
+    version: u32,
+    params: ConnParams,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerHello {
+    version: u32,
+    result: Result<ConnParams, Rejection>,
+}
+
+impl MessageTrait for ClientHello {}
+impl MessageTrait for ServerHello {}
+
+/// Why a server refused a connection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Rejection {
+    /// The first message was not a [`ClientHello`].
+    NotAHello,
+    /// The client speaks a different protocol version.
+    Version {
+        server: u32,
+        client: u32,
+    },
+    Framing(FramingMode),
+    Codec(Codec),
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotAHello => write!(f, "peer did not open with a handshake"),
+            Self::Version { server, client } => write!(
+                f,
+                "protocol version mismatch: server speaks v{}, client speaks v{}",
+                server, client
+            ),
+            Self::Framing(m) => write!(f, "framing mode {:?} is not supported", m),
+            Self::Codec(c) => write!(f, "codec {:?} is not supported", c),
+        }
+    }
+}
+
+/// Things that can go wrong during the handshake.
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// The server refused the connection (on the client), or we refused the client (on the
+    /// server).
+    Rejected(Rejection),
+    /// The server accepted, but with parameters we did not offer.
+    Unexpected(ConnParams),
+    /// The handshake could not be sent or received.
+    Transport(ProtocolError),
+}
+
+impl From<ProtocolError> for HandshakeError {
+    fn from(value: ProtocolError) -> Self {
+        Self::Transport(value)
+    }
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rejected(r) => write!(f, "rejected: {}", r),
+            Self::Unexpected(p) => write!(f, "server accepted unexpected parameters {:?}", p),
+            Self::Transport(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Client side: offer `params` and wait for the server's answer.
+pub fn connect<S: Transport>(
+    stream: &mut ChunkedTcpStream<S>,
+    params: ConnParams,
+) -> Result<ConnParams, HandshakeError> {
+    let hello = ClientHello {
+        magic: HELLO_MAGIC,
+        version: PROTOCOL_VERSION,
+        params,
+    };
+    send_frame(stream, &HELLO_PARAMS, &hello)?;
+
+    let reply: ServerHello = recv_frame(stream, &mut hello_decoder(), &mut hello_chunks())?;
+    match reply.result {
+        Ok(agreed)
+            if agreed.framing == params.framing
+                && agreed.codec == params.codec
+                && params.features.contains(agreed.features) =>
+        {
+            Ok(agreed)
+        }
+        Ok(agreed) => Err(HandshakeError::Unexpected(agreed)),
+        Err(r) => Err(HandshakeError::Rejected(r)),
+    }
+}
+
+/// Server side: read the client's offer and accept it if `caps` allows, otherwise tell
+/// the client why not.
+pub fn accept<S: Transport>(
+    stream: &mut ChunkedTcpStream<S>,
+    caps: &Capabilities,
+) -> Result<ConnParams, HandshakeError> {
+    let result = match recv_frame::<ClientHello, _>(stream, &mut hello_decoder(), &mut hello_chunks()) {
+        Ok(hello) => negotiate(&hello, caps),
+        // A peer that skipped the handshake sent something else entirely.
+        Err(ProtocolError::Decode(_) | ProtocolError::Oversize { .. }) => Err(Rejection::NotAHello),
+        Err(e) => return Err(e.into()),
+    };
+
+    let reply = ServerHello {
+        version: PROTOCOL_VERSION,
+        result: result.clone(),
+    };
+    send_frame(stream, &HELLO_PARAMS, &reply)?;
+    result.map_err(HandshakeError::Rejected)
+}
+
+/// [`accept`] for servers that move the bytes themselves: given the payload of the frame
+/// a [`hello_decoder`] produced, or `None` if it produced an error instead, append the
+/// encoded reply to `out` and return what was agreed.
+pub fn answer_hello(
+    payload: Option<&[u8]>,
+    caps: &Capabilities,
+    out: &mut Vec<u8>,
+) -> Result<ConnParams, HandshakeError> {
+    let result = match payload.map(ClientHello::from_bytes) {
+        Some(Ok(hello)) => negotiate(&hello, caps),
+        _ => Err(Rejection::NotAHello),
+    };
+
+    let reply = ServerHello {
+        version: PROTOCOL_VERSION,
+        result: result.clone(),
+    };
+    encode_frame(&reply, &HELLO_PARAMS, out)?;
+    result.map_err(HandshakeError::Rejected)
+}
+
+/// Decodes the client's hello, for [`answer_hello`].
+pub fn hello_decoder() -> FrameDecoder {
+    FrameDecoder::new(HELLO_PARAMS, HELLO_MAX_FRAME_LEN)
+}
+
+/// Room for a whole hello frame: the header chunk and the payload.
+fn hello_chunks() -> RecvChunks {
+    RecvChunks::new(1 + HELLO_MAX_FRAME_LEN / MSG_SIZE_BYTES)
+}
+
+fn negotiate(hello: &ClientHello, caps: &Capabilities) -> Result<ConnParams, Rejection> {
+    if hello.magic != HELLO_MAGIC {
+        return Err(Rejection::NotAHello);
+    }
+    if hello.version != PROTOCOL_VERSION {
+        return Err(Rejection::Version {
+            server: PROTOCOL_VERSION,
+            client: hello.version,
+        });
+    }
+    if !caps.framings.contains(&hello.params.framing) {
+        return Err(Rejection::Framing(hello.params.framing));
+    }
+    if !caps.codecs.contains(&hello.params.codec) {
+        return Err(Rejection::Codec(hello.params.codec));
+    }
+
+    Ok(ConnParams {
+        features: hello.params.features.intersection(caps.features),
+        ..hello.params
+    })
+}
+
+#[cfg(test)]
+mod t {
+    use super::{
+        accept, connect, hello_chunks, hello_decoder, Capabilities, ClientHello, Codec,
+        ConnParams, Features, HandshakeError, Rejection, HELLO_MAGIC, HELLO_PARAMS,
+        PROTOCOL_VERSION,
+    };
+    use crate::{
+        app::Work,
+        chunked_tcp_stream::stream_pair,
+        protocol::{recv_frame, send_frame, FramedConn},
+        serialize::ClientWorkPacket,
+    };
+    use std::thread;
+
+    #[test]
+    fn handshake_accepts_defaults() {
+        let (mut client, mut server) = stream_pair();
+        let server = thread::spawn(move || accept(&mut server, &Capabilities::default()));
+
+        let params = connect(&mut client, ConnParams::default()).expect("client handshake");
+        assert_eq!(params, ConnParams::default());
+        assert_eq!(server.join().unwrap().expect("server handshake"), params);
+    }
+
+    #[test]
+    fn handshake_drops_unsupported_features() {
+        let (mut client, mut server) = stream_pair();
+        let caps = Capabilities {
+            features: Features::NONE,
+            ..Default::default()
+        };
+        let server = thread::spawn(move || accept(&mut server, &caps));
+
+        let offer = ConnParams {
+            features: Features::CHECKSUM,
+            ..Default::default()
+        };
+        let params = connect(&mut client, offer).expect("client handshake");
+        assert_eq!(params.features, Features::NONE);
+        assert_eq!(server.join().unwrap().expect("server handshake"), params);
+    }
+
+    #[test]
+    fn handshake_rejects_version_mismatch() {
+        let (mut client, mut server) = stream_pair();
+        let server = thread::spawn(move || accept(&mut server, &Capabilities::default()));
+
+        let hello = ClientHello {
+            magic: HELLO_MAGIC,
+            version: PROTOCOL_VERSION + 1,
+            params: ConnParams::default(),
+        };
+        send_frame(&mut client, &HELLO_PARAMS, &hello).unwrap();
+        let reply: super::ServerHello = recv_frame(&mut client, &mut hello_decoder(), &mut hello_chunks()).unwrap();
+        let expected = Rejection::Version {
+            server: PROTOCOL_VERSION,
+            client: PROTOCOL_VERSION + 1,
+        };
+        assert_eq!(reply.result, Err(expected.clone()));
+        assert!(matches!(
+            server.join().unwrap(),
+            Err(HandshakeError::Rejected(r)) if r == expected
+        ));
+    }
+
+    #[test]
+    fn handshake_rejects_missing_hello() {
+        let (client, mut server) = stream_pair();
+        let server = thread::spawn(move || accept(&mut server, &Capabilities::default()));
+
+        let mut conn = FramedConn::<ClientWorkPacket, _>::new(client);
+        conn.send_msg(&ClientWorkPacket::new(0, Work::Immediate))
+            .unwrap();
+        assert!(matches!(
+            server.join().unwrap(),
+            Err(HandshakeError::Rejected(Rejection::NotAHello))
+        ));
+    }
+
+    #[test]
+    fn handshake_agrees_on_codec() {
+        let caps = Capabilities {
+            codecs: vec![Codec::Bincode, Codec::Fixed],
+            ..Default::default()
+        };
+        let (mut client, mut server) = stream_pair();
+        let server_caps = caps.clone();
+        let server = thread::spawn(move || {
+            let params = accept(&mut server, &server_caps).expect("server handshake");
+            FramedConn::<ClientWorkPacket, _>::new(server)
+                .with_params(params)
+                .recv_msg()
+                .expect("recv request")
+        });
+        let offer = ConnParams {
+            codec: Codec::Fixed,
+            ..Default::default()
+        };
+        let params = connect(&mut client, offer).expect("client handshake");
+        assert_eq!(params.codec, Codec::Fixed);
+        let req = ClientWorkPacket::new(4, Work::Const(2));
+        FramedConn::<ClientWorkPacket, _>::new(client)
+            .with_params(params)
+            .send_msg(&req)
+            .unwrap();
+        assert_eq!(server.join().unwrap(), req);
+
+        let (mut client, mut server) = stream_pair();
+        let server = thread::spawn(move || accept(&mut server, &caps));
+        let offer = ConnParams {
+            codec: Codec::Json,
+            ..Default::default()
+        };
+        assert!(matches!(
+            connect(&mut client, offer),
+            Err(HandshakeError::Rejected(Rejection::Codec(Codec::Json)))
+        ));
+        assert!(server.join().unwrap().is_err());
+    }
+}
+
@@ -1,51 +1,823 @@
-use crate::protocol::MSG_SIZE_BYTES;
-use std::os::fd::RawFd;
+use crate::{chunked_tcp_stream::IOV_MAX, protocol::MSG_SIZE_BYTES};
+use std::{
+    alloc::{self, Layout},
+    collections::VecDeque,
+    fmt, io, mem,
+    os::fd::RawFd,
+    ptr::NonNull,
+    sync::atomic::{AtomicU16, Ordering},
+    time::Duration,
+};
 
-/// HINT: here are some potentially useful imports from external libraries. You shouldn't need
-/// other external libraries. You can remove the allow(unused) in your code.
-#[allow(unused)]
 use io_uring::{cqueue, opcode, squeue, types, IoUring};
-#[allow(unused)]
-use libc::{iovec, msghdr, sockaddr_in};
-#[allow(unused)]
-use nix::sys::socket::{SockaddrIn, SockaddrLike};
+use libc::{iovec, msghdr};
 
+/// One chunk to move over a socket with [`IOUring::send_msgs`] or [`IOUring::recv_msgs`].
 pub struct RingMsg<'a> {
     pub raw_fd: RawFd,
     pub data: &'a mut [u8; MSG_SIZE_BYTES],
+    /// The caller's own tag; the ring leaves it alone.
     pub user_data: u64,
-    // TODO: students can add members to this struct.
+    /// Set once the transfer ends: the bytes moved, which is all of `data` unless a receive
+    /// hit end of stream, or the error that stopped it.
+    pub result: Option<io::Result<usize>>,
 }
 
-// ------------------------------------
-// TODO: students add structs and impls here
+impl<'a> RingMsg<'a> {
+    pub fn new(raw_fd: RawFd, data: &'a mut [u8; MSG_SIZE_BYTES], user_data: u64) -> Self {
+        Self {
+            raw_fd,
+            data,
+            user_data,
+            result: None,
+        }
+    }
+}
+
+/// The messages bound for one socket, moved in order by a single `sendmsg` or `recvmsg`
+/// of up to [`IOV_MAX`] messages in flight at a time. Its SQEs carry its index as their
+/// `user_data`.
+struct FdBatch {
+    fd: RawFd,
+    /// Indices of its messages, in order.
+    msgs: Vec<usize>,
+    /// What's left to move; the first entry may be part-way through its message.
+    iovecs: Vec<iovec>,
+    /// Messages already finished.
+    done: usize,
+    hdr: msghdr,
+}
+
+/// What to do with a batch after one of its completions.
+enum Next {
+    Resubmit,
+    Finished,
+}
+
+impl FdBatch {
+    fn entry(&mut self, send: bool, tag: u64) -> squeue::Entry {
+        self.hdr.msg_iov = self.iovecs[self.done..].as_mut_ptr();
+        self.hdr.msg_iovlen = (self.iovecs.len() - self.done).min(IOV_MAX);
+        let fd = types::Fd(self.fd);
+        let entry = if send {
+            opcode::SendMsg::new(fd, &self.hdr)
+                .flags(libc::MSG_NOSIGNAL as u32)
+                .build()
+        } else {
+            opcode::RecvMsg::new(fd, &mut self.hdr).build()
+        };
+        entry.user_data(tag)
+    }
+
+    /// Record the result `res` of the last submission in `msgs`.
+    fn complete(&mut self, res: i32, msgs: &mut [RingMsg], send: bool) -> Next {
+        if res == -libc::EINTR || res == -libc::EAGAIN {
+            return Next::Resubmit;
+        }
+        if res <= 0 {
+            // The message part-way through reports what it got before the stream ended.
+            for (i, &m) in self.msgs.iter().enumerate().skip(self.done) {
+                let moved = MSG_SIZE_BYTES - self.iovecs[i].iov_len;
+                msgs[m].result = Some(match res {
+                    0 if !send => Ok(moved),
+                    0 => Err(io::ErrorKind::WriteZero.into()),
+                    errno => Err(io::Error::from_raw_os_error(-errno)),
+                });
+            }
+            return Next::Finished;
+        }
+
+        let mut n = res as usize;
+        while n > 0 {
+            let iov = &mut self.iovecs[self.done];
+            let step = n.min(iov.iov_len);
+            // SAFETY: `step` is at most what's left of this message's buffer.
+            iov.iov_base = unsafe { iov.iov_base.cast::<u8>().add(step) }.cast();
+            iov.iov_len -= step;
+            n -= step;
+            if iov.iov_len == 0 {
+                msgs[self.msgs[self.done]].result = Some(Ok(MSG_SIZE_BYTES));
+                self.done += 1;
+            }
+        }
+        if self.done == self.msgs.len() {
+            Next::Finished
+        } else {
+            Next::Resubmit
+        }
+    }
+}
+
+/// A finished operation, as [`IOUring::wait`] reports it.
+#[derive(Debug, Clone, Copy)]
+pub struct Completion {
+    /// The tag the operation was submitted with.
+    pub user_data: u64,
+    /// What the kernel returned: a count or new descriptor, or a negated errno.
+    pub result: i32,
+    /// Whether a multishot operation will complete again.
+    pub more: bool,
+    /// The provided buffer the kernel picked, if it picked one; see [`BufRing`].
+    pub buffer: Option<u16>,
+}
+
+impl Completion {
+    /// [`Self::result`] as a count or descriptor, or the error it carries.
+    pub fn result(&self) -> io::Result<usize> {
+        if self.result < 0 {
+            Err(io::Error::from_raw_os_error(-self.result))
+        } else {
+            Ok(self.result as usize)
+        }
+    }
+}
+
+/// A descriptor as an operation names it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingFd {
+    Raw(RawFd),
+    /// A slot in the ring's file table; see [`IOUring::register_file_slots`].
+    Fixed(u32),
+}
+
+impl From<RawFd> for RingFd {
+    fn from(fd: RawFd) -> Self {
+        Self::Raw(fd)
+    }
+}
+
+/// How long an [`IOUring::timeout`] waits, kept where the kernel can read it.
+pub struct RingTimeout(types::Timespec);
+
+impl From<Duration> for RingTimeout {
+    fn from(after: Duration) -> Self {
+        Self(types::Timespec::from(after))
+    }
+}
+
+/// Receive buffers the kernel picks from for [`IOUring::recv_multishot`], so sockets with
+/// nothing to say hold none. A picked buffer is the receiver's until it hands it back with
+/// [`Self::give_back`]; while every buffer is out, receives fail with `ENOBUFS`.
+pub struct BufRing {
+    group: u16,
+    /// The ring of free buffers shared with the kernel; its first entry's last field is the
+    /// tail we advance.
+    entries: NonNull<types::BufRingEntry>,
+    count: u16,
+    tail: u16,
+    buf_len: usize,
+    bufs: Box<[u8]>,
+}
+
+impl BufRing {
+    /// `count` buffers of `buf_len` bytes each, all free, for group `group`. `count` must be
+    /// a power of two, at most 32768.
+    pub fn new(group: u16, count: u16, buf_len: usize) -> Result<Self, anyhow::Error> {
+        anyhow::ensure!(
+            count.is_power_of_two() && count <= 1 << 15,
+            "provided buffer count {} is not a power of two up to 32768",
+            count
+        );
+        // SAFETY: the layout isn't empty. All zeroes is a valid, empty ring.
+        let entries = unsafe { alloc::alloc_zeroed(Self::layout(count)) };
+        let mut ring = Self {
+            group,
+            entries: NonNull::new(entries.cast()).ok_or_else(|| anyhow::anyhow!("out of memory"))?,
+            count,
+            tail: 0,
+            buf_len,
+            bufs: vec![0; count as usize * buf_len].into_boxed_slice(),
+        };
+        for id in 0..count {
+            ring.give_back(id);
+        }
+        Ok(ring)
+    }
+
+    /// The kernel wants the ring page aligned.
+    fn layout(count: u16) -> Layout {
+        let size = count as usize * mem::size_of::<types::BufRingEntry>();
+        Layout::from_size_align(size, 4096).expect("buffer ring layout")
+    }
+
+    pub fn group(&self) -> u16 {
+        self.group
+    }
+
+    /// The first `len` bytes of buffer `id`, as a completion that picked it reports them.
+    pub fn get(&self, id: u16, len: usize) -> &[u8] {
+        let start = id as usize * self.buf_len;
+        &self.bufs[start..start + len.min(self.buf_len)]
+    }
+
+    /// Return buffer `id` for the kernel to pick again.
+    pub fn give_back(&mut self, id: u16) {
+        let mask = self.count - 1;
+        // SAFETY: the entry is inside the ring, and the kernel doesn't look at entries past
+        // the tail, which we only advance once the entry is filled in.
+        unsafe {
+            let entry = &mut *self.entries.as_ptr().add((self.tail & mask) as usize);
+            entry.set_addr(self.bufs.as_ptr().add(id as usize * self.buf_len) as u64);
+            entry.set_len(self.buf_len as u32);
+            entry.set_bid(id);
+            self.tail = self.tail.wrapping_add(1);
+            let tail = types::BufRingEntry::tail(self.entries.as_ptr()).cast_mut();
+            AtomicU16::from_ptr(tail).store(self.tail, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for BufRing {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `new` with the same layout.
+        unsafe { alloc::dealloc(self.entries.as_ptr().cast(), Self::layout(self.count)) };
+    }
+}
+
+/// How [`IOUring::new`] sets up a ring. Flags the kernel doesn't support fail setup
+/// instead of being dropped quietly.
+#[derive(Debug, Clone, PartialEq, Eq, clap::Args)]
+#[command(about = None, long_about = None)]
+pub struct RingSetup {
+    /// Submission queue entries. The kernel rounds up to a power of two.
+    #[arg(long = "ring-sz", default_value_t = 256, help = "Submission queue entries for the io_uring (iouring-0 only)")]
+    pub entries: u32,
+    /// Completion queue entries; twice `entries` when unset.
+    #[arg(long = "ring-cq-sz", value_name = "N", help = "Completion queue entries for the io_uring [default: twice --ring-sz]")]
+    pub cq_entries: Option<u32>,
+    /// Have a kernel thread poll the submission queue, so submitting needs no system call
+    /// while it's awake. It sleeps after this many idle milliseconds.
+    #[arg(long = "ring-sqpoll-idle-ms", value_name = "MS", help = "Poll the io_uring's submissions from a kernel thread that sleeps after this many idle milliseconds")]
+    pub sqpoll_idle_ms: Option<u32>,
+    /// The CPU the polling thread is pinned to.
+    #[arg(long = "ring-sqpoll-cpu", value_name = "CPU", requires = "sqpoll_idle_ms", help = "Pin the submission polling thread to this CPU")]
+    pub sqpoll_cpu: Option<u32>,
+    /// Run completion work when the task next enters the kernel instead of interrupting it.
+    #[arg(long = "ring-coop-taskrun", help = "Set IORING_SETUP_COOP_TASKRUN")]
+    pub coop_taskrun: bool,
+    /// Promise that only the thread that set the ring up submits to it.
+    #[arg(long = "ring-single-issuer", help = "Set IORING_SETUP_SINGLE_ISSUER")]
+    pub single_issuer: bool,
+    /// Defer completion work until the task waits for completions. Needs `single_issuer`.
+    #[arg(long = "ring-defer-taskrun", requires = "single_issuer", help = "Set IORING_SETUP_DEFER_TASKRUN (needs --ring-single-issuer)")]
+    pub defer_taskrun: bool,
+}
 
-// ------------------------------------
+impl Default for RingSetup {
+    fn default() -> Self {
+        Self {
+            entries: 256,
+            cq_entries: None,
+            sqpoll_idle_ms: None,
+            sqpoll_cpu: None,
+            coop_taskrun: false,
+            single_issuer: false,
+            defer_taskrun: false,
+        }
+    }
+}
+
+impl fmt::Display for RingSetup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "entries={}", self.entries)?;
+        if let Some(entries) = self.cq_entries {
+            write!(f, " cq_entries={}", entries)?;
+        }
+        match self.sqpoll_idle_ms {
+            Some(ms) => write!(f, " sqpoll_idle_ms={}", ms)?,
+            None => write!(f, " sqpoll=off")?,
+        }
+        if let Some(cpu) = self.sqpoll_cpu {
+            write!(f, " sqpoll_cpu={}", cpu)?;
+        }
+        write!(
+            f,
+            " coop_taskrun={} single_issuer={} defer_taskrun={}",
+            self.coop_taskrun as u32, self.single_issuer as u32, self.defer_taskrun as u32
+        )
+    }
+}
 
-#[allow(unused)]
 pub struct IOUring {
     // Students should NOT modify the members here
     ring: IoUring<squeue::Entry, cqueue::Entry>,
 }
 
 impl IOUring {
-    // TODO: Students implement this function
-    #[allow(unused)]
-    pub fn new(slots: u32) -> Result<Self, anyhow::Error> {
-        unimplemented!();
+    /// A ring set up as `setup` says.
+    pub fn new(setup: &RingSetup) -> Result<Self, anyhow::Error> {
+        let mut builder = IoUring::builder();
+        if let Some(entries) = setup.cq_entries {
+            builder.setup_cqsize(entries);
+        }
+        if let Some(ms) = setup.sqpoll_idle_ms {
+            builder.setup_sqpoll(ms);
+        }
+        if let Some(cpu) = setup.sqpoll_cpu {
+            builder.setup_sqpoll_cpu(cpu);
+        }
+        if setup.coop_taskrun {
+            builder.setup_coop_taskrun();
+        }
+        if setup.single_issuer {
+            builder.setup_single_issuer();
+        }
+        if setup.defer_taskrun {
+            builder.setup_defer_taskrun();
+        }
+        Ok(Self {
+            ring: builder.build(setup.entries)?,
+        })
+    }
+
+    /// `setup`, which this ring was set up with, as the kernel reports it: the queue sizes
+    /// it settled on, and whether it polls and expects a single issuer.
+    pub fn effective_setup(&self, setup: &RingSetup) -> RingSetup {
+        let params = self.ring.params();
+        RingSetup {
+            entries: params.sq_entries(),
+            cq_entries: Some(params.cq_entries()),
+            sqpoll_idle_ms: setup.sqpoll_idle_ms.filter(|_| params.is_setup_sqpoll()),
+            sqpoll_cpu: setup.sqpoll_cpu.filter(|_| params.is_setup_sqpoll()),
+            single_issuer: params.is_setup_single_issuer(),
+            ..setup.clone()
+        }
     }
 
-    // TODO: Students implement this function
-    #[allow(unused)]
+    /// Send every message's whole buffer, resubmitting after short sends. Messages for the
+    /// same socket go out in order. Each message's `result` says how it went; an `Err` here
+    /// means the ring itself failed.
     pub fn send_msgs(&mut self, msgs: &mut [RingMsg]) -> Result<(), anyhow::Error> {
-        unimplemented!();
+        self.transfer(msgs, true)
     }
 
-    // TODO: Students implement this function
-    #[allow(unused)]
+    /// Fill every message's buffer, resubmitting after short receives. Messages for the
+    /// same socket are filled in order. Each message's `result` says how it went; an `Err`
+    /// here means the ring itself failed.
     pub fn recv_msgs(&mut self, msgs: &mut [RingMsg]) -> Result<(), anyhow::Error> {
-        unimplemented!();
+        self.transfer(msgs, false)
+    }
+
+    /// Accept connections on `listener_fd` until the ring says otherwise: each completion
+    /// carries `user_data` and a new descriptor, and stops being [`Completion::more`] once
+    /// the accept needs submitting again.
+    pub fn accept_multishot(&mut self, listener_fd: RawFd, user_data: u64) -> Result<(), anyhow::Error> {
+        let entry = opcode::AcceptMulti::new(types::Fd(listener_fd))
+            .flags(libc::SOCK_CLOEXEC)
+            .build()
+            .user_data(user_data);
+        // SAFETY: accepting touches no memory of ours.
+        unsafe { self.push(&entry) }
+    }
+
+    /// Complete with `user_data` once `after` has passed.
+    ///
+    /// # Safety
+    ///
+    /// `after` must stay valid, and unchanged, until the completion arrives.
+    pub unsafe fn timeout(&mut self, after: &RingTimeout, user_data: u64) -> Result<(), anyhow::Error> {
+        self.push(&opcode::Timeout::new(&after.0).build().user_data(user_data))
     }
 
-    // TODO: students can make their own helper functions
+    /// Register `bufs` with the ring, so [`Self::read_fixed`] and [`Self::write_fixed`] can
+    /// name them by index instead of the kernel mapping them for every operation.
+    ///
+    /// # Safety
+    ///
+    /// Every buffer must stay valid for as long as the ring lives.
+    pub unsafe fn register_buffers(&mut self, bufs: &[iovec]) -> Result<(), anyhow::Error> {
+        Ok(self.ring.submitter().register_buffers(bufs)?)
+    }
+
+    /// Give the ring a file table of `slots` empty slots, for [`Self::set_file`] to fill.
+    /// Operations on [`RingFd::Fixed`] skip looking the descriptor up every time.
+    pub fn register_file_slots(&mut self, slots: u32) -> Result<(), anyhow::Error> {
+        Ok(self.ring.submitter().register_files_sparse(slots)?)
+    }
+
+    /// Point file table slot `slot` at `fd`, or empty it. The table holds its own
+    /// reference, so a slot must be emptied for closing its descriptor to take effect.
+    pub fn set_file(&mut self, slot: u32, fd: Option<RawFd>) -> Result<(), anyhow::Error> {
+        self.ring
+            .submitter()
+            .register_files_update(slot, &[fd.unwrap_or(-1)])?;
+        Ok(())
+    }
+
+    /// Let [`Self::recv_multishot`] pick buffers from `bufs`.
+    ///
+    /// # Safety
+    ///
+    /// `bufs` must outlive the ring.
+    pub unsafe fn register_buf_ring(&mut self, bufs: &BufRing) -> Result<(), anyhow::Error> {
+        let addr = bufs.entries.as_ptr() as u64;
+        Ok(self.ring.submitter().register_buf_ring(addr, bufs.count, bufs.group)?)
+    }
+
+    /// Receive from `fd` into buffers from group `group` until the stream ends or a receive
+    /// fails, completing with `user_data` and [`Completion::buffer`] each time data arrives.
+    /// The receive is over once a completion isn't [`Completion::more`].
+    pub fn recv_multishot(&mut self, fd: impl Into<RingFd>, group: u16, user_data: u64) -> Result<(), anyhow::Error> {
+        let op = match fd.into() {
+            RingFd::Raw(fd) => opcode::RecvMulti::new(types::Fd(fd), group),
+            RingFd::Fixed(slot) => opcode::RecvMulti::new(types::Fixed(slot), group),
+        };
+        // SAFETY: the kernel only writes into buffers it was given through a `BufRing`,
+        // which outlives the ring.
+        unsafe { self.push(&op.build().user_data(user_data)) }
+    }
+
+    /// Receive up to `len` bytes from `fd` into `buf`, completing with `user_data`.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid for `len` bytes, and untouched, until the completion arrives.
+    pub unsafe fn recv(&mut self, fd: impl Into<RingFd>, buf: *mut u8, len: usize, user_data: u64) -> Result<(), anyhow::Error> {
+        let len = len.min(u32::MAX as usize) as u32;
+        let op = match fd.into() {
+            RingFd::Raw(fd) => opcode::Recv::new(types::Fd(fd), buf, len),
+            RingFd::Fixed(slot) => opcode::Recv::new(types::Fixed(slot), buf, len),
+        };
+        self.push(&op.build().user_data(user_data))
+    }
+
+    /// Send up to `len` bytes from `buf` to `fd`, completing with `user_data`.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must stay valid for `len` bytes, and unchanged, until the completion arrives.
+    pub unsafe fn send(&mut self, fd: impl Into<RingFd>, buf: *const u8, len: usize, user_data: u64) -> Result<(), anyhow::Error> {
+        let len = len.min(u32::MAX as usize) as u32;
+        let op = match fd.into() {
+            RingFd::Raw(fd) => opcode::Send::new(types::Fd(fd), buf, len),
+            RingFd::Fixed(slot) => opcode::Send::new(types::Fixed(slot), buf, len),
+        };
+        self.push(&op.flags(libc::MSG_NOSIGNAL).build().user_data(user_data))
+    }
+
+    /// [`Self::recv`] into registered buffer `buf_index`, which `buf` points into.
+    ///
+    /// # Safety
+    ///
+    /// As for [`Self::recv`], and `buf..buf + len` must lie inside buffer `buf_index`.
+    pub unsafe fn read_fixed(
+        &mut self,
+        fd: impl Into<RingFd>,
+        buf: *mut u8,
+        len: usize,
+        buf_index: u16,
+        user_data: u64,
+    ) -> Result<(), anyhow::Error> {
+        let len = len.min(u32::MAX as usize) as u32;
+        let op = match fd.into() {
+            RingFd::Raw(fd) => opcode::ReadFixed::new(types::Fd(fd), buf, len, buf_index),
+            RingFd::Fixed(slot) => opcode::ReadFixed::new(types::Fixed(slot), buf, len, buf_index),
+        };
+        self.push(&op.build().user_data(user_data))
+    }
+
+    /// [`Self::send`] from registered buffer `buf_index`, which `buf` points into.
+    ///
+    /// # Safety
+    ///
+    /// As for [`Self::send`], and `buf..buf + len` must lie inside buffer `buf_index`.
+    pub unsafe fn write_fixed(
+        &mut self,
+        fd: impl Into<RingFd>,
+        buf: *const u8,
+        len: usize,
+        buf_index: u16,
+        user_data: u64,
+    ) -> Result<(), anyhow::Error> {
+        let len = len.min(u32::MAX as usize) as u32;
+        let op = match fd.into() {
+            RingFd::Raw(fd) => opcode::WriteFixed::new(types::Fd(fd), buf, len, buf_index),
+            RingFd::Fixed(slot) => opcode::WriteFixed::new(types::Fixed(slot), buf, len, buf_index),
+        };
+        self.push(&op.build().user_data(user_data))
+    }
+
+    /// Submit everything queued, wait for at least one completion, and append every
+    /// completion ready to `out`.
+    pub fn wait(&mut self, out: &mut Vec<Completion>) -> Result<(), anyhow::Error> {
+        loop {
+            match self.ring.submit_and_wait(1) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                r => r?,
+            };
+            let before = out.len();
+            out.extend(self.ring.completion().map(|cqe| Completion {
+                user_data: cqe.user_data(),
+                result: cqe.result(),
+                more: cqueue::more(cqe.flags()),
+                buffer: cqueue::buffer_select(cqe.flags()),
+            }));
+            if out.len() > before {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Queue `entry`, submitting what's already queued first if the queue is full.
+    ///
+    /// # Safety
+    ///
+    /// Whatever `entry` points at must outlive the operation.
+    unsafe fn push(&mut self, entry: &squeue::Entry) -> Result<(), anyhow::Error> {
+        while self.ring.submission().push(entry).is_err() {
+            match self.ring.submit() {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                r => r?,
+            };
+        }
+        Ok(())
+    }
+
+    fn transfer(&mut self, msgs: &mut [RingMsg], send: bool) -> Result<(), anyhow::Error> {
+        let mut batches: Vec<FdBatch> = Vec::new();
+        for (i, msg) in msgs.iter_mut().enumerate() {
+            msg.result = None;
+            let iov = iovec {
+                iov_base: msg.data.as_mut_ptr().cast(),
+                iov_len: MSG_SIZE_BYTES,
+            };
+            match batches.iter_mut().find(|b| b.fd == msg.raw_fd) {
+                Some(batch) => {
+                    batch.msgs.push(i);
+                    batch.iovecs.push(iov);
+                }
+                None => batches.push(FdBatch {
+                    fd: msg.raw_fd,
+                    msgs: vec![i],
+                    iovecs: vec![iov],
+                    done: 0,
+                    // SAFETY: all zeroes is a valid, empty msghdr.
+                    hdr: unsafe { mem::zeroed() },
+                }),
+            }
+        }
+
+        // `batches` doesn't move from here on, so the msghdrs the kernel holds stay put.
+        let capacity = self.ring.params().sq_entries() as usize;
+        let mut pending: VecDeque<usize> = (0..batches.len()).collect();
+        let mut in_flight = 0;
+        while in_flight > 0 || !pending.is_empty() {
+            while in_flight < capacity {
+                let Some(&b) = pending.front() else { break };
+                let entry = batches[b].entry(send, b as u64);
+                // SAFETY: the msghdr, its iovecs and the buffers they point to outlive the
+                // submission, since we wait for every completion before returning.
+                if unsafe { self.ring.submission().push(&entry) }.is_err() {
+                    break;
+                }
+                pending.pop_front();
+                in_flight += 1;
+            }
+
+            match self.ring.submit_and_wait(1) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                r => r?,
+            };
+            for cqe in self.ring.completion() {
+                in_flight -= 1;
+                let b = cqe.user_data() as usize;
+                if let Next::Resubmit = batches[b].complete(cqe.result(), msgs, send) {
+                    pending.push_back(b);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::{BufRing, Completion, IOUring, RingFd, RingMsg, RingSetup, MSG_SIZE_BYTES};
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        os::{
+            fd::{AsRawFd, FromRawFd},
+            unix::net::UnixStream,
+        },
+        thread,
+        time::Duration,
+    };
+
+    fn ring(entries: u32) -> IOUring {
+        IOUring::new(&RingSetup {
+            entries,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn ring_moves_chunks_in_order() {
+        // One slot for two sockets, so one waits its turn.
+        let mut ring = ring(1);
+        let pairs: Vec<_> = (0..2).map(|_| UnixStream::pair().unwrap()).collect();
+
+        let mut out: Vec<[u8; MSG_SIZE_BYTES]> = (0..12).map(|i| [i as u8; MSG_SIZE_BYTES]).collect();
+        let mut msgs: Vec<_> = out
+            .iter_mut()
+            .enumerate()
+            .map(|(i, data)| RingMsg::new(pairs[i % 2].0.as_raw_fd(), data, i as u64))
+            .collect();
+        ring.send_msgs(&mut msgs).unwrap();
+        assert!(msgs.iter().all(|m| matches!(m.result, Some(Ok(MSG_SIZE_BYTES)))));
+
+        let mut back = [[0u8; MSG_SIZE_BYTES]; 12];
+        let mut msgs: Vec<_> = back
+            .iter_mut()
+            .enumerate()
+            .map(|(i, data)| RingMsg::new(pairs[i % 2].1.as_raw_fd(), data, i as u64))
+            .collect();
+        ring.recv_msgs(&mut msgs).unwrap();
+        assert!(msgs.iter().all(|m| matches!(m.result, Some(Ok(MSG_SIZE_BYTES)))));
+        for (i, chunk) in back.iter().enumerate() {
+            assert_eq!(chunk, &[i as u8; MSG_SIZE_BYTES]);
+        }
+    }
+
+    #[test]
+    fn ring_resumes_short_receives_and_reports_errors() {
+        let mut ring = ring(4);
+        let (reader, mut writer) = UnixStream::pair().unwrap();
+        let writer = thread::spawn(move || {
+            writer.write_all(&[1; 50]).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            writer.write_all(&[2; MSG_SIZE_BYTES + 10]).unwrap();
+        });
+
+        let mut bufs = [[0u8; MSG_SIZE_BYTES]; 2];
+        let mut msgs: Vec<_> = bufs
+            .iter_mut()
+            .map(|data| RingMsg::new(reader.as_raw_fd(), data, 0))
+            .collect();
+        ring.recv_msgs(&mut msgs).unwrap();
+        writer.join().unwrap();
+        assert!(matches!(msgs[0].result, Some(Ok(MSG_SIZE_BYTES))));
+        // The writer closed after 60 bytes of the second chunk.
+        assert!(matches!(msgs[1].result, Some(Ok(60))));
+        assert_eq!(&bufs[0][..50], &[1; 50]);
+        assert_eq!(&bufs[0][50..], &[2; MSG_SIZE_BYTES - 50]);
+
+        let mut data = [0u8; MSG_SIZE_BYTES];
+        let mut msgs = [RingMsg::new(-1, &mut data, 0)];
+        ring.send_msgs(&mut msgs).unwrap();
+        assert!(matches!(&msgs[0].result, Some(Err(e)) if e.raw_os_error() == Some(libc::EBADF)));
+    }
+
+    #[test]
+    fn ring_accepts_many_and_moves_bytes() {
+        let mut ring = ring(4);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        ring.accept_multishot(listener.as_raw_fd(), 7).unwrap();
+
+        // Connect one at a time, so each accepted descriptor is known to be whose.
+        let mut completions: Vec<Completion> = Vec::new();
+        let mut conns = Vec::new();
+        for _ in 0..3 {
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            ring.wait(&mut completions).unwrap();
+            let accept = completions.pop().unwrap();
+            assert_eq!(accept.user_data, 7);
+            assert!(accept.more, "one accept serves every connection");
+            // SAFETY: the ring just accepted this descriptor, and nothing else owns it.
+            let server = unsafe { TcpStream::from_raw_fd(accept.result().unwrap() as i32) };
+            conns.push((client, server));
+        }
+
+        let (client, server) = &mut conns[1];
+        let fd = server.as_raw_fd();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 16];
+        // SAFETY: `buf` outlives the wait that reaps this receive.
+        unsafe { ring.recv(fd, buf.as_mut_ptr(), buf.len(), 1) }.unwrap();
+        ring.wait(&mut completions).unwrap();
+        let recv = completions.pop().unwrap();
+        assert_eq!((recv.user_data, recv.result().unwrap()), (1, 4));
+
+        // SAFETY: as above.
+        unsafe { ring.send(fd, buf.as_ptr(), 4, 2) }.unwrap();
+        ring.wait(&mut completions).unwrap();
+        assert_eq!(completions.pop().unwrap().result().unwrap(), 4);
+        let mut back = [0u8; 4];
+        client.read_exact(&mut back).unwrap();
+        assert_eq!(&back, b"ping");
+    }
+
+    #[test]
+    fn ring_moves_bytes_through_registered_buffers_and_files() {
+        let mut ring = ring(4);
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        let mut bufs = [[0u8; 64]; 2];
+        let iovecs: Vec<_> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            })
+            .collect();
+        // SAFETY: `bufs` outlives `ring`.
+        unsafe { ring.register_buffers(&iovecs) }.unwrap();
+        ring.register_file_slots(2).unwrap();
+        ring.set_file(1, Some(theirs.as_raw_fd())).unwrap();
+
+        ours.write_all(b"hello").unwrap();
+        let mut completions: Vec<Completion> = Vec::new();
+        // SAFETY: buffer 0 outlives the wait that reaps this read.
+        unsafe { ring.read_fixed(RingFd::Fixed(1), iovecs[0].iov_base.cast(), 64, 0, 0) }.unwrap();
+        ring.wait(&mut completions).unwrap();
+        let n = completions.pop().unwrap().result().unwrap();
+        assert_eq!(n, 5);
+
+        // SAFETY: as above, for buffer 1.
+        unsafe {
+            let reply = iovecs[1].iov_base.cast::<u8>();
+            reply.copy_from(iovecs[0].iov_base.cast(), n);
+            ring.write_fixed(RingFd::Fixed(1), reply, n, 1, 0).unwrap();
+        }
+        ring.wait(&mut completions).unwrap();
+        assert_eq!(completions.pop().unwrap().result().unwrap(), 5);
+        let mut back = [0u8; 5];
+        ours.read_exact(&mut back).unwrap();
+        assert_eq!(&back, b"hello");
+
+        // An emptied slot no longer names the socket.
+        ring.set_file(1, None).unwrap();
+        // SAFETY: as above.
+        unsafe { ring.write_fixed(RingFd::Fixed(1), iovecs[1].iov_base.cast(), n, 1, 0) }.unwrap();
+        ring.wait(&mut completions).unwrap();
+        assert_eq!(completions.pop().unwrap().result().unwrap_err().raw_os_error(), Some(libc::EBADF));
+    }
+
+    #[test]
+    fn multishot_receives_pick_provided_buffers() {
+        let mut ring = ring(4);
+        let mut bufs = BufRing::new(3, 2, 16).unwrap();
+        // SAFETY: `bufs` outlives `ring`, which is dropped first.
+        unsafe { ring.register_buf_ring(&bufs) }.unwrap();
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        ring.recv_multishot(theirs.as_raw_fd(), bufs.group(), 9).unwrap();
+
+        let mut completions: Vec<Completion> = Vec::new();
+        let mut recv = |ours: &mut UnixStream, data: &[u8]| {
+            ours.write_all(data).unwrap();
+            ring.wait(&mut completions).unwrap();
+            completions.pop().unwrap()
+        };
+        let first = recv(&mut ours, b"one");
+        let second = recv(&mut ours, b"two");
+        assert!(first.more && second.more);
+        let (a, b) = (first.buffer.unwrap(), second.buffer.unwrap());
+        assert_ne!(a, b);
+        assert_eq!(bufs.get(a, first.result().unwrap()), b"one");
+        assert_eq!(bufs.get(b, second.result().unwrap()), b"two");
+
+        // Both buffers are out, so the receive ends.
+        let starved = recv(&mut ours, b"three");
+        assert!(!starved.more);
+        assert_eq!(starved.result().unwrap_err().raw_os_error(), Some(libc::ENOBUFS));
+
+        // Handed back, a buffer takes what was waiting.
+        bufs.give_back(a);
+        ring.recv_multishot(theirs.as_raw_fd(), bufs.group(), 9).unwrap();
+        ring.wait(&mut completions).unwrap();
+        let third = completions.pop().unwrap();
+        assert_eq!(third.buffer, Some(a));
+        assert_eq!(bufs.get(a, third.result().unwrap()), b"three");
+    }
+
+    #[test]
+    fn ring_setup_takes_effect() {
+        let setup = RingSetup {
+            entries: 3,
+            cq_entries: Some(64),
+            coop_taskrun: true,
+            single_issuer: true,
+            defer_taskrun: true,
+            ..Default::default()
+        };
+        let mut ring = IOUring::new(&setup).unwrap();
+        let effective = ring.effective_setup(&setup);
+        // The kernel rounds the queues up to powers of two.
+        assert_eq!((effective.entries, effective.cq_entries), (4, Some(64)));
+        assert_eq!(
+            effective.to_string(),
+            "entries=4 cq_entries=64 sqpoll=off coop_taskrun=1 single_issuer=1 defer_taskrun=1"
+        );
+
+        // Deferred completions still arrive once we wait for them.
+        let (mut ours, theirs) = UnixStream::pair().unwrap();
+        ours.write_all(b"hi").unwrap();
+        let mut buf = [0u8; 2];
+        let mut completions = Vec::new();
+        // SAFETY: `buf` outlives the wait that reaps this receive.
+        unsafe { ring.recv(theirs.as_raw_fd(), buf.as_mut_ptr(), 2, 0) }.unwrap();
+        ring.wait(&mut completions).unwrap();
+        assert_eq!(completions[0].result().unwrap(), 2);
+        assert_eq!(&buf, b"hi");
+    }
 }
@@ -1,78 +1,743 @@
-use crate::{io_uring::IOUring, serialize::ClientWorkPacket};
+//! io_uring_server.rs
+use crate::{
+    config::ServerConfig,
+    handshake::{self, ConnParams},
+    io_uring::{BufRing, Completion, IOUring, RingFd, RingSetup, RingTimeout},
+    protocol::{encode_frame, FrameDecoder, ProtocolError, MSG_SIZE_BYTES, RECV_CHUNKS},
+    serialize::{ClientMessage, MessageTrait, ServerMessage},
+    shutdown::{Registration, Shutdown},
+    stats::ServerStats,
+    transport::{Endpoint, Listener, Stream},
+};
 use std::{
-    error::Error,
-    fmt,
-    net::{SocketAddrV4, TcpStream},
+    io, mem,
+    net::{Shutdown as SocketShutdown, TcpStream},
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::net::UnixStream,
+    },
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
 };
 
-// TODO: Students should implement this function. Probably start by deleting the current body.
-#[allow(unused)]
-pub fn io_uring_server(addr: SocketAddrV4, ring_sz: usize) {
-    let _stream: TcpStream = unimplemented!();
-    #[allow(unreachable_code)]
-    handle_conn(_stream, ring_sz).unwrap();
-}
+/// Bytes one receive asks for, and one fixed write sends.
+const RECV_BUF_LEN: usize = RECV_CHUNKS * MSG_SIZE_BYTES;
+
+/// Connections that get registered buffers and a fixed file slot, when those are on. Any
+/// more use plain operations.
+const FIXED_CONNS: usize = 256;
+
+/// What a completion is for: the low bits of its `user_data`. The rest is the connection's
+/// slot.
+const ACCEPT: u64 = 0;
+const RECV: u64 = 1;
+const SEND: u64 = 2;
+const SWEEP: u64 = 3;
+const OP_BITS: u32 = 2;
 
-// TODO: Students should implement this function. Students should not change the function signature
-// of this thing. Probably start by deleting the current body.
-#[allow(unused)]
-fn handle_conn(stream: TcpStream, ring_sz: usize) -> Result<(), anyhow::Error> {
-    let _server: IOUringServer = unimplemented!();
-    #[allow(unreachable_code)]
-    _server.serve()
+/// Serve every connection from one thread and one ring set up as `setup`, which also
+/// accepts them. With `fixed`, the first [`FIXED_CONNS`] connections move their bytes
+/// through buffers and descriptors registered with the ring. With `provided_bufs`, every
+/// connection receives into that many buffers shared through the ring instead of one of
+/// its own. Connections silent for `config.idle_timeout` are closed within half as long
+/// again. Returns once shutdown has begun and every connection has closed.
+pub fn io_uring_server(
+    endpoint: Endpoint,
+    config: ServerConfig,
+    stats: Arc<ServerStats>,
+    shutdown: Arc<Shutdown>,
+    setup: RingSetup,
+    fixed: bool,
+    provided_bufs: Option<u16>,
+) {
+    let listener = Listener::bind(&endpoint).expect("failed to bind listener");
+    config
+        .socket
+        .apply_listener(&listener)
+        .expect("failed to set socket options")
+        .log("io_uring_server listener");
+    println!("io_uring_server listening on {} (backlog {})", endpoint, config.socket.backlog);
+
+    let ring = IOUring::new(&setup).expect("failed to set up io_uring");
+    println!("io_uring_server ring setup: {}", ring.effective_setup(&setup));
+    let mut server = IOUringServer::new(ring, &listener, &config, &stats, &shutdown);
+    if fixed {
+        server.register_fixed(FIXED_CONNS).expect("failed to register with io_uring");
+        println!("io_uring_server registered buffers and files for {} connections", FIXED_CONNS);
+    }
+    if let Some(count) = provided_bufs {
+        server.provide_bufs(count).expect("failed to register provided buffers");
+        println!("io_uring_server receiving into {} provided buffers", count);
+    }
+    server.serve().expect("io_uring failed");
 }
 
-// TODO: Students should use this struct to store objects used in the functions
-// above. Students can change the struct's type signature if they want.
-#[allow(unused)]
-struct IOUringServer {
+struct IOUringServer<'a> {
     ring: IOUring,
-    // TODO: Students can add their own members
+    listener: &'a Listener,
+    config: &'a ServerConfig,
+    stats: &'a ServerStats,
+    shutdown: &'a Shutdown,
+    /// Open connections, by slot.
+    conns: Vec<Option<Conn<'a>>>,
+    /// Slots of closed connections, to reuse.
+    free: Vec<usize>,
+    /// Where each slot receives, unless buffers are provided. Boxed, so they stay put for
+    /// the kernel as this grows.
+    recv_bufs: Vec<Box<[u8; RECV_BUF_LEN]>>,
+    /// Where each fixed slot stages its replies for a fixed write; empty unless fixed
+    /// operations are on. The slots below its length are the fixed ones.
+    send_bufs: Vec<Box<[u8; RECV_BUF_LEN]>>,
+    /// Buffers every connection receives into, if provided; they outlive the ring, which is
+    /// dropped first.
+    provided: Option<BufRing>,
+    /// How often to look for idle connections, if they time out. The kernel reads it while
+    /// serving, when this can't move.
+    sweep: Option<RingTimeout>,
+}
+
+/// One connection's state between completions.
+struct Conn<'a> {
+    stream: Stream,
+    _registration: Registration<'a>,
+    /// `None` while `decoder` is still reading the client's hello.
+    params: Option<ConnParams>,
+    /// Holds whatever part of a frame has arrived so far.
+    decoder: FrameDecoder,
+    /// Replies handed to the send in flight, if any; unless the slot is fixed, the kernel
+    /// reads them from here, so they stay put until it is done. The first `sent` bytes are
+    /// gone.
+    sending: Vec<u8>,
+    sent: usize,
+    /// Replies encoded since then.
+    queued: Vec<u8>,
+    recv_in_flight: bool,
+    send_in_flight: bool,
+    /// Receive nothing more, and close once the replies have gone out.
+    closing: bool,
+    /// When the client last sent anything.
+    last_heard: Instant,
+}
+
+impl<'a> IOUringServer<'a> {
+    fn new(
+        ring: IOUring,
+        listener: &'a Listener,
+        config: &'a ServerConfig,
+        stats: &'a ServerStats,
+        shutdown: &'a Shutdown,
+    ) -> Self {
+        Self {
+            ring,
+            listener,
+            config,
+            stats,
+            shutdown,
+            conns: Vec::new(),
+            free: Vec::new(),
+            recv_bufs: Vec::new(),
+            send_bufs: Vec::new(),
+            provided: None,
+            sweep: config.idle_timeout.map(|idle| RingTimeout::from(idle / 2)),
+        }
+    }
+
+    /// Register a receive and a send buffer for each of the first `slots` slots, receive
+    /// buffers first, and a file table with a slot for each.
+    fn register_fixed(&mut self, slots: usize) -> Result<(), anyhow::Error> {
+        self.recv_bufs = (0..slots).map(|_| Box::new([0; RECV_BUF_LEN])).collect();
+        self.send_bufs = (0..slots).map(|_| Box::new([0; RECV_BUF_LEN])).collect();
+        let iovecs: Vec<_> = self
+            .recv_bufs
+            .iter_mut()
+            .chain(self.send_bufs.iter_mut())
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: RECV_BUF_LEN,
+            })
+            .collect();
+        // SAFETY: the buffers are boxed and only ever added to, so they live as long as
+        // the ring, which is dropped first.
+        unsafe { self.ring.register_buffers(&iovecs)? };
+        self.ring.register_file_slots(slots as u32)
+    }
+
+    /// Receive into `count` buffers shared by every connection, each handed back to the
+    /// ring as soon as its bytes are parsed.
+    fn provide_bufs(&mut self, count: u16) -> Result<(), anyhow::Error> {
+        let bufs = BufRing::new(0, count, RECV_BUF_LEN)?;
+        // SAFETY: `provided` outlives `ring`.
+        unsafe { self.ring.register_buf_ring(&bufs)? };
+        self.provided = Some(bufs);
+        Ok(())
+    }
+
+    /// Run until shutdown has begun and no connection is left open.
+    fn serve(&mut self) -> Result<(), anyhow::Error> {
+        self.ring.accept_multishot(self.listener.as_raw_fd(), ACCEPT)?;
+        if let Some(every) = &self.sweep {
+            // SAFETY: `sweep` stays put while serving.
+            unsafe { self.ring.timeout(every, SWEEP)? };
+        }
+        let mut completions = Vec::new();
+        loop {
+            self.ring.wait(&mut completions)?;
+            for completion in completions.drain(..) {
+                self.complete(completion)?;
+            }
+            if self.shutdown.is_requested() && self.conns.iter().all(Option::is_none) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn complete(&mut self, completion: Completion) -> Result<(), anyhow::Error> {
+        let slot = (completion.user_data >> OP_BITS) as usize;
+        match completion.user_data & ((1 << OP_BITS) - 1) {
+            ACCEPT => {
+                if !completion.more {
+                    self.ring.accept_multishot(self.listener.as_raw_fd(), ACCEPT)?;
+                }
+                match completion.result() {
+                    Ok(fd) => self.open(fd as RawFd)?,
+                    Err(e) => eprintln!("[io_uring_server] Incoming connection error: {}", e),
+                }
+                return Ok(());
+            }
+            RECV => {
+                let conn = self.conns[slot].as_mut().expect("completion for a closed slot");
+                conn.recv_in_flight &= completion.more;
+                let result = completion.result();
+                if matches!(&result, Err(e) if e.raw_os_error() == Some(libc::ENOBUFS)) {
+                    // The receive ended; `advance` posts it again.
+                    self.stats.buffer_exhaustions.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    let got = match (&self.provided, completion.buffer) {
+                        (Some(bufs), Some(id)) => result.map(|n| bufs.get(id, n)),
+                        // Only the end of the stream comes without a buffer.
+                        (Some(_), None) => result.map(|_| &[][..]),
+                        (None, _) => result.map(|n| &self.recv_bufs[slot][..n]),
+                    };
+                    conn.received(got, self.config, self.stats, self.shutdown);
+                }
+                // The decoder has copied what it needs, so the buffer can go round again.
+                if let (Some(bufs), Some(id)) = (&mut self.provided, completion.buffer) {
+                    bufs.give_back(id);
+                }
+            }
+            SEND => {
+                let conn = self.conns[slot].as_mut().expect("completion for a closed slot");
+                conn.send_in_flight = false;
+                conn.sent(completion.result(), self.stats);
+            }
+            SWEEP => {
+                let idle = self.config.idle_timeout.expect("sweeping without an idle timeout");
+                let now = Instant::now();
+                for conn in self.conns.iter_mut().flatten() {
+                    if now.duration_since(conn.last_heard) >= idle {
+                        conn.time_out(self.stats);
+                    }
+                }
+                if let Some(every) = &self.sweep {
+                    // SAFETY: as in `serve`.
+                    unsafe { self.ring.timeout(every, SWEEP)? };
+                }
+                return Ok(());
+            }
+            op => unreachable!("unknown io_uring operation {}", op),
+        }
+        self.advance(slot)
+    }
+
+    /// Set up a connection the ring just accepted as `fd`.
+    fn open(&mut self, fd: RawFd) -> Result<(), anyhow::Error> {
+        self.stats.connections.fetch_add(1, Ordering::Relaxed);
+        // SAFETY: the ring just accepted `fd`, so nothing else owns it.
+        let stream = unsafe {
+            match self.listener {
+                Listener::Tcp(_) => Stream::Tcp(TcpStream::from_raw_fd(fd)),
+                Listener::Unix(_) => Stream::Unix(UnixStream::from_raw_fd(fd)),
+                Listener::Shm(_) => unreachable!("only the tcp server speaks shared memory"),
+            }
+        };
+        match self.config.socket.apply(&stream) {
+            Ok(effective) => effective.log_once("io_uring_server"),
+            Err(e) => {
+                self.stats.record_close("io_uring_server", &e.into());
+                return Ok(());
+            }
+        }
+        let registration = match self.shutdown.register(&stream) {
+            Ok(registration) => registration,
+            Err(e) => {
+                self.stats.record_close("io_uring_server", &e.into());
+                return Ok(());
+            }
+        };
+
+        let slot = self.free.pop().unwrap_or(self.conns.len());
+        if slot < self.send_bufs.len() {
+            self.ring.set_file(slot as u32, Some(fd))?;
+        }
+        let conn = Conn {
+            stream,
+            _registration: registration,
+            params: None,
+            decoder: handshake::hello_decoder(),
+            sending: Vec::new(),
+            sent: 0,
+            queued: Vec::new(),
+            recv_in_flight: false,
+            send_in_flight: false,
+            closing: false,
+            last_heard: Instant::now(),
+        };
+        if slot == self.conns.len() {
+            self.conns.push(None);
+        }
+        if self.provided.is_none() && slot == self.recv_bufs.len() {
+            self.recv_bufs.push(Box::new([0; RECV_BUF_LEN]));
+        }
+        self.conns[slot] = Some(conn);
+        self.advance(slot)
+    }
+
+    /// Start whatever `slot` is ready for next: sending queued replies, receiving more, or,
+    /// once it has nothing left in flight, closing.
+    fn advance(&mut self, slot: usize) -> Result<(), anyhow::Error> {
+        let conn = self.conns[slot].as_mut().expect("advancing a closed slot");
+        let fixed_slots = self.send_bufs.len();
+        let fixed = slot < fixed_slots;
+        let fd = if fixed {
+            RingFd::Fixed(slot as u32)
+        } else {
+            RingFd::Raw(conn.stream.as_raw_fd())
+        };
+        let tag = (slot as u64) << OP_BITS;
+
+        if !conn.send_in_flight {
+            if conn.sent == conn.sending.len() {
+                conn.sending.clear();
+                conn.sent = 0;
+                mem::swap(&mut conn.sending, &mut conn.queued);
+            }
+            if conn.sent < conn.sending.len() {
+                let rest = &conn.sending[conn.sent..];
+                if fixed {
+                    let staged = &mut self.send_bufs[slot];
+                    let len = rest.len().min(RECV_BUF_LEN);
+                    staged[..len].copy_from_slice(&rest[..len]);
+                    // SAFETY: the staging buffer is registered as this index, and is left
+                    // alone until this write completes.
+                    unsafe {
+                        let index = (fixed_slots + slot) as u16;
+                        self.ring.write_fixed(fd, staged.as_ptr(), len, index, tag | SEND)?
+                    };
+                } else {
+                    // SAFETY: `sending` is left alone until this send completes, and the
+                    // connection stays open until then.
+                    unsafe { self.ring.send(fd, rest.as_ptr(), rest.len(), tag | SEND)? };
+                }
+                conn.send_in_flight = true;
+            }
+        }
+        if !conn.closing && !conn.recv_in_flight {
+            if let Some(bufs) = &self.provided {
+                self.ring.recv_multishot(fd, bufs.group(), tag | RECV)?;
+            } else {
+                let buf = self.recv_bufs[slot].as_mut_ptr();
+                // SAFETY: the slot's receive buffer is boxed, so it stays put, and is left
+                // alone until this receive completes. Fixed slots' are registered as the
+                // slot.
+                unsafe {
+                    if fixed {
+                        self.ring.read_fixed(fd, buf, RECV_BUF_LEN, slot as u16, tag | RECV)?
+                    } else {
+                        self.ring.recv(fd, buf, RECV_BUF_LEN, tag | RECV)?
+                    }
+                };
+            }
+            conn.recv_in_flight = true;
+        }
+
+        // A multishot receive only ends with the stream, so end that.
+        if conn.closing && conn.recv_in_flight && self.provided.is_some() {
+            let _ = conn.stream.shutdown(SocketShutdown::Read);
+        }
+
+        if conn.closing && !conn.recv_in_flight && !conn.send_in_flight {
+            if fixed {
+                self.ring.set_file(slot as u32, None)?;
+            }
+            self.conns[slot] = None;
+            self.free.push(slot);
+        }
+        Ok(())
+    }
 }
 
-impl IOUringServer {
-    // TODO: Students should implement this function
-    fn serve(self) -> Result<(), anyhow::Error> {
-        unimplemented!();
+impl Conn<'_> {
+    /// Take in what a receive got, answering every frame it completes.
+    fn received(
+        &mut self,
+        result: io::Result<&[u8]>,
+        config: &ServerConfig,
+        stats: &ServerStats,
+        shutdown: &Shutdown,
+    ) {
+        if self.closing {
+            return;
+        }
+        let err = match result {
+            Ok([]) => self.decoder.eof_error(),
+            Ok(bytes) => {
+                self.last_heard = Instant::now();
+                self.decoder.feed(bytes);
+                self.handle_frames(config, stats);
+                return;
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock) => return,
+            Err(e) => e.into(),
+        };
+
+        self.closing = true;
+        match self.params {
+            None => stats.record_handshake_failure("io_uring_server", &err.into()),
+            // Shutting down stops reads, so this is the end we asked for.
+            Some(_) if shutdown.is_requested() => self.queue_replies(&[ServerMessage::Goodbye], stats),
+            Some(_) => stats.record_close("io_uring_server", &err),
+        }
+    }
+
+    /// Close the connection for having gone quiet, cutting off the receive in flight.
+    fn time_out(&mut self, stats: &ServerStats) {
+        if self.closing {
+            return;
+        }
+        self.closing = true;
+        let err = ProtocolError::Io(io::ErrorKind::TimedOut.into());
+        match self.params {
+            None => stats.record_handshake_failure("io_uring_server", &err.into()),
+            Some(_) => stats.record_close("io_uring_server", &err),
+        }
+        let _ = self.stream.shutdown(SocketShutdown::Both);
     }
 
-    // TODO: Students should implement this function
-    #[allow(unused)]
-    fn handle_recv_msgs(&mut self) -> Result<(), anyhow::Error> {
-        unimplemented!();
+    fn handle_frames(&mut self, config: &ServerConfig, stats: &ServerStats) {
+        while !self.closing {
+            let payload = match self.decoder.next_frame() {
+                Ok(Some(payload)) => payload,
+                Ok(None) => return,
+                Err(e @ ProtocolError::Checksum { .. }) if self.params.is_some() => {
+                    stats.record_checksum_failure("io_uring_server", &e);
+                    continue;
+                }
+                Err(e) => {
+                    self.closing = true;
+                    match self.params {
+                        None => {
+                            let _ = handshake::answer_hello(None, &config.capabilities, &mut self.queued);
+                            stats.record_handshake_failure("io_uring_server", &e.into());
+                        }
+                        Some(_) => stats.record_close("io_uring_server", &e),
+                    }
+                    return;
+                }
+            };
+            match self.params {
+                None => self.answer_hello(&payload, config, stats),
+                Some(params) => self.handle_request(&payload, params, config, stats),
+            }
+        }
     }
 
-    // TODO: Students should implement this function
-    #[allow(unused)]
-    fn do_work_request(&mut self, request: ClientWorkPacket) -> Result<(), anyhow::Error> {
-        unimplemented!();
+    fn answer_hello(&mut self, payload: &[u8], config: &ServerConfig, stats: &ServerStats) {
+        match handshake::answer_hello(Some(payload), &config.capabilities, &mut self.queued) {
+            Ok(params) => {
+                // The client waits for our answer before sending anything else, so the
+                // hello decoder has nothing more buffered.
+                self.params = Some(params);
+                self.decoder = FrameDecoder::new(params, config.max_frame_len);
+            }
+            Err(e) => {
+                self.closing = true;
+                stats.record_handshake_failure("io_uring_server", &e);
+            }
+        }
     }
 
-    // TODO: Students should implement this function
-    // Receives data from the ring and writes them to self.ring.recv_msgs.
-    // Returns error if ring returns an error
-    #[allow(unused)]
-    fn recv_msgs_from_ring(&mut self) -> Result<(), anyhow::Error> {
-        unimplemented!();
+    fn handle_request(&mut self, payload: &[u8], params: ConnParams, config: &ServerConfig, stats: &ServerStats) {
+        let received = Instant::now();
+        match ClientMessage::decode(params.codec, payload) {
+            Ok(ClientMessage::Goodbye) => {
+                stats.clean_disconnects.fetch_add(1, Ordering::Relaxed);
+                self.closing = true;
+                self.queue_replies(&[ServerMessage::Goodbye], stats);
+            }
+            Ok(request) => {
+                let replies = request.do_work(config, received);
+                stats.record_replies(request.len(), &replies);
+                self.queue_replies(&replies, stats);
+            }
+            Err(e) => {
+                // The frame itself was fine, so the connection can carry on.
+                let reply = ClientMessage::undecodable(params.codec, payload, &e);
+                stats.record_replies(0, std::slice::from_ref(&reply));
+                self.queue_replies(&[reply], stats);
+            }
+        }
     }
 
-    // TODO: Students should implement this function
-    // Sends whatver is in self.send_msgs. Checks each msg (draining the vec)
-    // and return an error if any one of them failed.
-    #[allow(unused)]
-    fn send_messages_to_ring(&mut self) -> Result<(), anyhow::Error> {
-        unimplemented!();
+    fn queue_replies(&mut self, replies: &[ServerMessage], stats: &ServerStats) {
+        let params = self.params.expect("replying before the handshake");
+        for reply in replies {
+            if let Err(e) = encode_frame(reply, &params, &mut self.queued) {
+                self.closing = true;
+                stats.record_close("io_uring_server", &e);
+                return;
+            }
+        }
     }
 
-    // TODO: Students can implement any other methods here
+    /// Take in what a send got through.
+    fn sent(&mut self, result: io::Result<usize>, stats: &ServerStats) {
+        let err = match result {
+            Ok(0) => io::ErrorKind::WriteZero.into(),
+            Ok(n) => {
+                self.sent += n;
+                return;
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock) => return,
+            Err(e) => e,
+        };
+
+        // Nothing more can reach the client, so stop the receive in flight too.
+        if !self.closing {
+            stats.record_close("io_uring_server", &err.into());
+        }
+        self.closing = true;
+        self.sending.clear();
+        self.sent = 0;
+        self.queued.clear();
+        let _ = self.stream.shutdown(SocketShutdown::Both);
+    }
 }
 
-#[derive(Debug)]
-struct UnexpectedError(String);
+#[cfg(test)]
+mod t {
+    use super::IOUringServer;
+    use crate::{
+        app::Work,
+        chunked_tcp_stream::ChunkedTcpStream,
+        config::ServerConfig,
+        handshake::{self, ConnParams},
+        io_uring::{IOUring, RingSetup},
+        protocol::{encode_frame, FramedReceiver, FramedSender, ProtocolError},
+        serialize::{ClientMessage, ClientWorkPacket, ServerMessage},
+        shutdown::{self, Shutdown},
+        stats::ServerStats,
+        transport::{Endpoint, Listener, Stream},
+    };
+    use std::{
+        io::Write,
+        sync::{atomic::Ordering, Arc},
+        thread,
+        time::Duration,
+    };
+
+    struct Served {
+        endpoint: Endpoint,
+        stats: Arc<ServerStats>,
+        shutdown: Arc<Shutdown>,
+        thread: thread::JoinHandle<()>,
+    }
+
+    impl Served {
+        /// Shut the server down and wait for its thread to finish.
+        fn stop(self) {
+            self.shutdown.begin();
+            // The ring only looks at shutdown when something completes.
+            drop(Stream::connect(&self.endpoint));
+            self.thread.join().unwrap();
+        }
+    }
+
+    /// Serve on a loopback port from a thread of its own, with `fixed_slots` fixed slots
+    /// and `provided_bufs` provided buffers.
+    fn serve(config: ServerConfig, fixed_slots: usize, provided_bufs: Option<u16>) -> Served {
+        let listener = Listener::bind(&Endpoint::Tcp("127.0.0.1:0".parse().unwrap())).unwrap();
+        let endpoint = match &listener {
+            Listener::Tcp(l) => Endpoint::Tcp(l.local_addr().unwrap()),
+            _ => unreachable!(),
+        };
+        let stats = Arc::new(ServerStats::default());
+        let shutdown = Arc::new(Shutdown::default());
+        let (server_stats, server_shutdown) = (stats.clone(), shutdown.clone());
+        let thread = thread::spawn(move || {
+            let ring = IOUring::new(&RingSetup::default()).unwrap();
+            let mut server = IOUringServer::new(ring, &listener, &config, &server_stats, &server_shutdown);
+            if fixed_slots > 0 {
+                server.register_fixed(fixed_slots).unwrap();
+            }
+            if let Some(count) = provided_bufs {
+                server.provide_bufs(count).unwrap();
+            }
+            server.serve().unwrap();
+        });
+        Served {
+            endpoint,
+            stats,
+            shutdown,
+            thread,
+        }
+    }
+
+    struct Client {
+        tx: FramedSender<ClientMessage>,
+        rx: FramedReceiver<ServerMessage>,
+        /// For writing frames a piece at a time.
+        raw: Stream,
+        params: ConnParams,
+    }
+
+    fn connect(endpoint: &Endpoint) -> Client {
+        let stream = Stream::connect(endpoint).unwrap();
+        let raw = stream.try_clone().unwrap();
+        let mut stream = ChunkedTcpStream::new(stream);
+        let params = handshake::connect(&mut stream, ConnParams::default()).unwrap();
+        Client {
+            tx: FramedSender::new(stream.try_clone().unwrap()).with_params(params),
+            rx: FramedReceiver::new(stream).with_params(params),
+            raw,
+            params,
+        }
+    }
+
+    fn work(id: u64) -> ClientMessage {
+        ClientMessage::Work(ClientWorkPacket::new(id, Work::Immediate))
+    }
+
+    /// Send requests `ids` back to back, then check each is answered, in order.
+    fn pipeline(conn: &mut Client, ids: std::ops::Range<u64>) {
+        for id in ids.clone() {
+            conn.tx.send_msg(&work(id)).unwrap();
+        }
+        for id in ids {
+            match conn.rx.recv_msg().unwrap() {
+                ServerMessage::Work(reply) => assert_eq!(reply.client_id(), id),
+                other => panic!("expected a reply to {}, got {:?}", id, other),
+            }
+        }
+    }
+
+    fn say_goodbye(conn: &mut Client) {
+        conn.tx.send_msg(&ClientMessage::Goodbye).unwrap();
+        assert!(matches!(conn.rx.recv_msg().unwrap(), ServerMessage::Goodbye));
+        assert!(conn.rx.recv_msg().unwrap_err().is_clean_eof());
+    }
+
+    #[test]
+    fn serves_a_loopback_client() {
+        let server = serve(ServerConfig::default(), 0, None);
+        let mut conn = connect(&server.endpoint);
+
+        // Half a frame, then the rest once the first half has surely been received alone.
+        let mut frame = Vec::new();
+        encode_frame(&work(1), &conn.params, &mut frame).unwrap();
+        let (head, tail) = frame.split_at(frame.len() / 2);
+        conn.raw.write_all(head).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        conn.raw.write_all(tail).unwrap();
+        match conn.rx.recv_msg().unwrap() {
+            ServerMessage::Work(reply) => assert_eq!(reply.client_id(), 1),
+            other => panic!("expected a reply, got {:?}", other),
+        }
+
+        pipeline(&mut conn, 2..34);
+        say_goodbye(&mut conn);
+        assert_eq!(server.stats.clean_disconnects.load(Ordering::Relaxed), 1);
+        assert_eq!(server.stats.requests.load(Ordering::Relaxed), 33);
+        server.stop();
+    }
+
+    #[test]
+    fn closes_idle_connections() {
+        let config = ServerConfig {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let server = serve(config, 0, None);
+        let mut conn = connect(&server.endpoint);
+        pipeline(&mut conn, 0..1);
+        // Long enough for the server, not forever if it never closes.
+        conn.raw.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+
+        let err = conn.rx.recv_msg().unwrap_err();
+        assert!(matches!(err, ProtocolError::Eof), "{:?}", err);
+        assert_eq!(server.stats.idle_timeouts.load(Ordering::Relaxed), 1);
+        server.stop();
+    }
+
+    #[test]
+    fn fixed_slots_run_out_into_plain_operations() {
+        let server = serve(ServerConfig::default(), 1, None);
+        // One connection takes the only fixed slot, the next does without.
+        let mut fixed = connect(&server.endpoint);
+        let mut plain = connect(&server.endpoint);
+        pipeline(&mut fixed, 0..16);
+        pipeline(&mut plain, 16..32);
+
+        // Replies larger than a staging buffer go out in several fixed writes.
+        let batch = (32..544).map(|id| ClientWorkPacket::new(id, Work::Immediate)).collect();
+        fixed.tx.send_msg(&ClientMessage::Batch(batch)).unwrap();
+        match fixed.rx.recv_msg().unwrap() {
+            ServerMessage::Batch(replies) => {
+                assert!(replies.iter().map(|r| r.client_id()).eq(32..544));
+            }
+            other => panic!("expected a batched reply, got {:?}", other),
+        }
+
+        // The freed fixed slot goes to the next connection.
+        say_goodbye(&mut fixed);
+        let mut reused = connect(&server.endpoint);
+        pipeline(&mut reused, 544..560);
+        pipeline(&mut plain, 560..576);
+        say_goodbye(&mut reused);
+        say_goodbye(&mut plain);
+        assert_eq!(server.stats.clean_disconnects.load(Ordering::Relaxed), 3);
+        server.stop();
+    }
+
+    #[test]
+    fn provided_buffers_run_out_and_come_back() {
+        let server = serve(ServerConfig::default(), 0, Some(2));
+        let mut conn = connect(&server.endpoint);
+        pipeline(&mut conn, 0..16);
+
+        // One write of a frame filling both buffers several times over, so the receive
+        // runs out of them partway through and has to be posted again.
+        let batch: Vec<_> = (16..1016).map(|id| ClientWorkPacket::new(id, Work::Immediate)).collect();
+        let mut frame = Vec::new();
+        encode_frame(&ClientMessage::Batch(batch), &conn.params, &mut frame).unwrap();
+        assert!(frame.len() > 2 * super::RECV_BUF_LEN);
+        conn.raw.write_all(&frame).unwrap();
+        match conn.rx.recv_msg().unwrap() {
+            ServerMessage::Batch(replies) => {
+                assert!(replies.iter().map(|r| r.client_id()).eq(16..1016));
+            }
+            other => panic!("expected a batched reply, got {:?}", other),
+        }
+        assert!(server.stats.buffer_exhaustions.load(Ordering::Relaxed) >= 1);
+
+        pipeline(&mut conn, 1016..1032);
+        say_goodbye(&mut conn);
+        server.stop();
+    }
 
-impl fmt::Display for UnexpectedError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
+    #[test]
+    fn drains_on_shutdown() {
+        let server = serve(ServerConfig::default(), 0, None);
+        shutdown::assert_drains(Stream::connect(&server.endpoint).unwrap(), &server.shutdown);
+        server.stop();
     }
 }
-impl Error for UnexpectedError {}
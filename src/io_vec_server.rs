@@ -1,32 +1,44 @@
 //! io_vec_server.rs
 use crate::{
-    chunked_tcp_stream::{writev, MSG_SIZE_BYTES},
-    serialize::{ClientWorkPacket, MessageTrait},
+    chunked_tcp_stream::{writev_all, ChunkedTcpStream, RecvChunks, MSG_SIZE_BYTES},
+    config::ServerConfig,
+    handshake::{self, ConnParams, HandshakeError},
+    protocol::{encode_frame, FrameDecoder, ProtocolError, RECV_CHUNKS},
+    serialize::{ClientMessage, MessageTrait, ServerMessage},
+    shutdown::Shutdown,
+    stats::ServerStats,
+    transport::{Accepted, Endpoint, Listener, Stream},
 };
 use libc::iovec;
 use std::{
-    io::{Read, Write},
-    net::{SocketAddrV4, TcpListener, TcpStream},
     os::fd::AsRawFd,
+    sync::{atomic::Ordering, Arc},
     thread,
+    time::Instant,
 };
 
 // TODO: Students will have to implement this function
-pub fn io_vec_server(addr: SocketAddrV4) {
-    let listener = TcpListener::bind(addr).expect("failed to bind TCP listener");
-    println!("io_vec_server listening on {}", addr);
+pub fn io_vec_server(
+    endpoint: Endpoint,
+    config: ServerConfig,
+    stats: Arc<ServerStats>,
+    shutdown: Arc<Shutdown>,
+) {
+    let listener = Listener::bind(&endpoint).expect("failed to bind listener");
+    config
+        .socket
+        .apply_listener(&listener)
+        .expect("failed to set socket options")
+        .log("io_vec_server listener");
+    println!("io_vec_server listening on {} (backlog {})", endpoint, config.socket.backlog);
 
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                thread::spawn(move || {
-                    let mut server = IOVecServer { stream };
-                    if let Err(e) = server.handle_conn() {
-                        if e.downcast_ref::<std::io::Error>().map_or(true, |io_err| io_err.kind() != std::io::ErrorKind::UnexpectedEof) {
-                            eprintln!("[io_vec_server] Connection error: {}", e);
-                        }
-                    }
-                });
+                let config = config.clone();
+                let stats = stats.clone();
+                let shutdown = shutdown.clone();
+                thread::spawn(move || handle_conn(stream, &config, &stats, &shutdown));
             }
             Err(e) => {
                 eprintln!("[io_vec_server] Incoming connection error: {}", e);
@@ -35,59 +47,161 @@ pub fn io_vec_server(addr: SocketAddrV4) {
     }
 }
 
+fn handle_conn(accepted: Accepted, config: &ServerConfig, stats: &ServerStats, shutdown: &Shutdown) {
+    stats.connections.fetch_add(1, Ordering::Relaxed);
+    let stream = match accepted.establish(config.idle_timeout) {
+        Ok(stream) => stream,
+        Err(e) => {
+            stats.record_handshake_failure("io_vec_server", &ProtocolError::Io(e).into());
+            return;
+        }
+    };
+    match config.socket.apply(&stream) {
+        Ok(effective) => effective.log_once("io_vec_server"),
+        Err(e) => {
+            stats.record_close("io_vec_server", &e.into());
+            return;
+        }
+    }
+    let _registration = match shutdown.register(&stream) {
+        Ok(registration) => registration,
+        Err(e) => {
+            stats.record_close("io_vec_server", &e.into());
+            return;
+        }
+    };
+    let mut server = match IOVecServer::accept(stream, config) {
+        Ok(server) => server,
+        Err(e) => {
+            stats.record_handshake_failure("io_vec_server", &e);
+            return;
+        }
+    };
+    if let Err(e) = server.handle_conn(config, stats, shutdown) {
+        stats.record_close("io_vec_server", &e);
+    }
+}
+
 struct IOVecServer {
-    stream: TcpStream,
+    stream: Stream,
+    params: ConnParams,
+    decoder: FrameDecoder,
+    chunks: RecvChunks,
 }
 
 impl IOVecServer {
+    /// Run the handshake on a freshly accepted connection.
+    fn accept(stream: Stream, config: &ServerConfig) -> Result<Self, HandshakeError> {
+        stream
+            .set_read_timeout(config.idle_timeout)
+            .map_err(ProtocolError::Io)?;
+        let mut handshake_stream = ChunkedTcpStream::new(
+            stream.try_clone().map_err(ProtocolError::Io)?,
+        );
+        let params = handshake::accept(&mut handshake_stream, &config.capabilities)?;
+
+        Ok(Self {
+            stream,
+            params,
+            decoder: FrameDecoder::new(params, config.max_frame_len).with_read_ahead(),
+            chunks: RecvChunks::new(RECV_CHUNKS),
+        })
+    }
+
     // TODO: Students will have to implement this function.
     // Students SHOULD use chunked_tcp_stream::writev/readv which checks to
     // make sure messages are no larger than MSG_SIZE_BYTES. It is a
     // thin wrapper around libc::writev/readv.
-    fn handle_conn(&mut self) -> Result<(), anyhow::Error> {
+    fn handle_conn(
+        &mut self,
+        config: &ServerConfig,
+        stats: &ServerStats,
+        shutdown: &Shutdown,
+    ) -> Result<(), ProtocolError> {
         loop {
-            let mut first_chunk = [0u8; MSG_SIZE_BYTES];
-            self.stream.read_exact(&mut first_chunk)?;
-            let total_len = u64::from_be_bytes(first_chunk[..8].try_into()?) as usize;
-
-            let mut received_data = Vec::with_capacity(total_len);
-
-            let first_data_len = (total_len).min(MSG_SIZE_BYTES - 8);
-            received_data.extend_from_slice(&first_chunk[8..8 + first_data_len]);
-
-            while received_data.len() < total_len {
-                let mut chunk_buf = [0u8; MSG_SIZE_BYTES];
-                self.stream.read_exact(&mut chunk_buf)?;
-                let bytes_to_take = (total_len - received_data.len()).min(MSG_SIZE_BYTES);
-                received_data.extend_from_slice(&chunk_buf[..bytes_to_take]);
-            }
+            let payload = match self.recv_payload() {
+                Ok(payload) => payload,
+                // Shutting down stops reads, so this is the error we asked for.
+                Err(_) if shutdown.is_requested() => {
+                    return self.send_replies(&[ServerMessage::Goodbye]);
+                }
+                Err(e @ ProtocolError::Checksum { .. }) => {
+                    stats.record_checksum_failure("io_vec_server", &e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let received = Instant::now();
+            let request = match ClientMessage::decode(self.params.codec, &payload) {
+                Ok(ClientMessage::Goodbye) => {
+                    stats.clean_disconnects.fetch_add(1, Ordering::Relaxed);
+                    return self.send_replies(&[ServerMessage::Goodbye]);
+                }
+                Ok(request) => request,
+                Err(e) => {
+                    // The frame itself was fine, so the connection can carry on.
+                    let reply = ClientMessage::undecodable(self.params.codec, &payload, &e);
+                    stats.record_replies(0, std::slice::from_ref(&reply));
+                    self.send_replies(&[reply])?;
+                    continue;
+                }
+            };
+            let replies = request.do_work(config, received);
+            stats.record_replies(request.len(), &replies);
+            self.send_replies(&replies)?;
+        }
+    }
 
-            let request = ClientWorkPacket::from_bytes(&received_data)?;
+    /// Send `replies` with as few writevs as the socket allows.
+    fn send_replies(&mut self, replies: &[ServerMessage]) -> Result<(), ProtocolError> {
+        let mut response_data = Vec::new();
+        for reply in replies {
+            encode_frame(reply, &self.params, &mut response_data)?;
+        }
 
-            let response_packet = request.do_work();
-            
-            let mut response_data = Vec::new();
-            response_packet.to_vec(&mut response_data)?;
-            let response_len = response_data.len() as u64;
+        let mut iovecs: Vec<iovec> = Vec::new();
+        for chunk in response_data.chunks(MSG_SIZE_BYTES) {
+            let iov = iovec {
+                iov_base: chunk.as_ptr() as *mut _,
+                iov_len: chunk.len(), 
+            };
+            iovecs.push(iov);
+        }
 
-            self.stream.write_all(&response_len.to_be_bytes())?;
+        // SAFETY: every iovec points into `response_data`, which outlives the call.
+        unsafe { writev_all(self.stream.as_raw_fd(), &mut iovecs)? };
+        Ok(())
+    }
 
-            let mut iovecs: Vec<iovec> = Vec::new();
-            for chunk in response_data.chunks(MSG_SIZE_BYTES) {
-                let iov = iovec {
-                    iov_base: chunk.as_ptr() as *mut _,
-                    iov_len: chunk.len(), 
-                };
-                iovecs.push(iov);
+    /// The next request frame's payload, not yet deserialized. Each readv takes the rest of
+    /// the frame and whatever requests are queued behind it.
+    fn recv_payload(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        loop {
+            if let Some(payload) = self.decoder.next_frame()? {
+                return Ok(payload);
             }
 
-            let iovecs_len = iovecs.len() as i32;
-        
-            unsafe {
-                writev(self.stream.as_raw_fd(), &mut iovecs, iovecs_len);
+            let (at_least, at_most) = self.decoder.read_bounds(self.chunks.capacity());
+            let (n, result) = self
+                .chunks
+                .readv_from(self.stream.as_raw_fd(), at_least, at_most);
+            // Keep what arrived before a timeout, so a retry picks up where this left off.
+            self.decoder.feed(self.chunks.filled(n));
+            result?;
+            if n < at_least {
+                return Err(self.decoder.eof_error());
             }
         }
     }
+}
+
+#[cfg(test)]
+mod t {
+    use super::handle_conn;
+    use crate::{config::ServerConfig, shutdown::drains_on_shutdown};
 
-    // TODO: Students can add helper functions here
+    #[test]
+    fn drains_in_order_on_shutdown() {
+        drains_on_shutdown(handle_conn, ServerConfig::default());
+    }
 }
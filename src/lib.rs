@@ -3,13 +3,25 @@
 pub mod app;
 pub mod chunked_tcp_stream;
 pub mod closed_loop_client;
+pub mod config;
+pub mod handshake;
 pub mod io_uring;
 pub mod io_uring_server;
 pub mod io_vec_server;
 pub mod open_loop_client;
+pub mod pipeline;
 pub mod protocol;
 pub mod serialize;
+pub mod shm;
+pub mod shutdown;
+pub mod sockopt;
+pub mod stats;
 pub mod tcp_server;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
+pub mod udp_client;
+pub mod udp_server;
 
 pub fn get_current_time_micros() -> u64 {
     use std::time::{SystemTime, UNIX_EPOCH};
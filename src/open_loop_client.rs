@@ -1,15 +1,16 @@
 use crate::{
     app::Work,
     chunked_tcp_stream::ChunkedTcpStream,
-    protocol::work_request::ClientWorkPacketConn,
-    protocol::work_response::ServerWorkPacketConn,
-    serialize::{ClientWorkPacket, LatencyRecord},
+    config::ClientConfig,
+    handshake,
+    protocol::{FramedReceiver, FramedSender, ProtocolError},
+    serialize::{ClientMessage, ClientWorkPacket, LatencyRecord, ServerMessage},
+    stats::ClientStats,
+    transport::{Endpoint, Stream},
     get_current_time_micros,
 };
 
 use std::{
-    io, // 导入 io 模块以使用 ErrorKind
-    net::{SocketAddrV4, TcpStream},
     path::PathBuf,
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
@@ -21,23 +22,25 @@ use std::{
 
 use minstant::Instant;
 use csv::Writer;
-use rand_distr::Distribution;
 
 fn client_open_loop(
-    send_stream: TcpStream,
+    mut sender: FramedSender<ClientMessage>,
     thread_start_time: Instant,
     thread_delay: Duration, // This is the constant delay for P0
     runtime: Duration,
     packets_sent: Arc<AtomicU64>,
     work: Work,
+    config: &ClientConfig,
 ) {
+    let batch_size = config.batch_size;
+    let heartbeat_interval = config.heartbeat_interval;
     // --- REMOVE THE POISSON LOGIC FOR PROJECT 0 ---
     // let mut rng = rand::thread_rng();
     // let lambda = 1. / thread_delay.as_secs_f64(); 
     // let exp = rand_distr::Exp::new(lambda).unwrap(); 
     
-    let mut sender = ClientWorkPacketConn::new(ChunkedTcpStream::new(send_stream));
     let mut id: u64 = 0;
+    let mut pings_sent: u64 = 0;
 
     // --- ADD ABSOLUTE SCHEDULING ---
     let mut next_send_time = thread_start_time;
@@ -52,10 +55,20 @@ fn client_open_loop(
         // Calculate the next send time based on a constant rate
         next_send_time += thread_delay;
         
-        let now = Instant::now();
-        if next_send_time > now {
-            // If we have time, sleep until the next scheduled send
-            thread::sleep(next_send_time - now);
+        let mut now = Instant::now();
+        while next_send_time > now {
+            // If we have time, sleep until the next scheduled send. Long gaps are broken up
+            // with pings so that the server doesn't take us for dead.
+            if next_send_time - now > heartbeat_interval {
+                thread::sleep(heartbeat_interval);
+                if sender.send_msg(&ClientMessage::Ping(pings_sent)).is_err() {
+                    return;
+                }
+                pings_sent += 1;
+            } else {
+                thread::sleep(next_send_time - now);
+            }
+            now = Instant::now();
         }
         // If we are *behind* schedule (now >= next_send_time),
         // this loop will run again immediately to "catch up".
@@ -66,32 +79,39 @@ fn client_open_loop(
         }
         // --- END OF PACING FIX ---
 
-        let work_packet = ClientWorkPacket::new(id, work);
-        if sender.send_work_msg(work_packet).is_err() {
+        let msg = if batch_size > 1 {
+            ClientMessage::Batch(
+                (id..id + batch_size as u64)
+                    .map(|id| ClientWorkPacket::new(id, work))
+                    .collect(),
+            )
+        } else {
+            ClientMessage::Work(ClientWorkPacket::new(id, work))
+        };
+        if sender.send_msg(&msg).is_err() {
             break;
         }
 
-        packets_sent.fetch_add(1, Ordering::SeqCst);
-        id += 1;
+        packets_sent.fetch_add(batch_size as u64, Ordering::SeqCst);
+        id += batch_size as u64;
     }
-}
 
-// Add this import at the top of the file
-use std::io;
+    // The server answers everything sent so far and then says goodbye back, which is what
+    // ends the receive loop. If the connection is already broken the receiver finds out.
+    let _ = sender.send_msg(&ClientMessage::Goodbye);
+}
 
 fn client_recv_loop(
-    recv_stream: TcpStream,
+    mut receiver: FramedReceiver<ServerMessage>,
     receiver_complete: Arc<AtomicBool>,
     packets_sent: Arc<AtomicU64>, // <-- ADD THIS ARGUMENT
+    stats: Arc<ClientStats>,
+    server_addr: Endpoint,
+    dead_after: Duration,
 ) -> Vec<LatencyRecord> {
-    
-    // --- SET A READ TIMEOUT ---
-    // This prevents the receiver from blocking forever if the connection is idle
-    recv_stream.set_read_timeout(Some(Duration::from_millis(500)))
-        .expect("Failed to set read timeout");
-
-    let mut receiver = ServerWorkPacketConn::new(ChunkedTcpStream::new(recv_stream));
     let mut latencies: Vec<LatencyRecord> = Vec::new();
+    // Anything from the server, pongs included, shows it is alive.
+    let mut last_heard = Instant::now();
     
     // --- ADD A PACKET COUNTER ---
     let mut packets_received = 0u64;
@@ -99,45 +119,51 @@ fn client_recv_loop(
     loop {
         // --- NEW, ROBUST LOOP LOGIC ---
         let is_complete = receiver_complete.load(Ordering::SeqCst);
-        let total_sent = packets_sent.load(Ordering::Relaxed);
-        
-        // Exit condition: sender is done AND we've received all sent packets.
-        if is_complete && packets_received >= total_sent {
-            break;
-        }
 
-        match receiver.recv_work_msg() {
+        // Exit condition: the server's goodbye, which comes after every reply.
+        match receiver.recv_msg() {
+            Ok(ServerMessage::Goodbye) => break,
             Ok(msg) => {
-                if let Some(lat) = msg.calculate_latency(get_current_time_micros()) {
-                    latencies.push(lat);
+                last_heard = Instant::now();
+                let recv_time = get_current_time_micros();
+                for packet in msg.into_packets() {
+                    // Failed responses are counted by code and have no latency.
+                    if !stats.record_failure(&packet) {
+                        if let Some(lat) = packet.calculate_latency(recv_time) {
+                            latencies.push(lat);
+                        }
+                    }
+                    packets_received += 1; // Increment received counter
                 }
-                packets_received += 1; // Increment received counter
+            }
+            Err(e @ ProtocolError::Checksum { .. }) => {
+                // The response arrived, but we can't trust what it says.
+                last_heard = Instant::now();
+                stats.record_checksum_failure(&e);
+                packets_received += 1;
             }
             Err(e) => {
-                // Check if the error is a timeout
-                if let Some(io_err) = e.downcast_ref::<io::Error>() {
-                    if io_err.kind() == io::ErrorKind::WouldBlock || io_err.kind() == io::ErrorKind::TimedOut {
-                        // It's just a timeout.
-                        // If the sender is done but we're still missing packets,
-                        // this timeout allows us to loop again and re-check the exit condition.
-                        // If sender isn't done, we just continue waiting.
-                        continue;
+                if e.is_timeout() {
+                    if last_heard.elapsed() >= dead_after {
+                        stats.record_dead_server(&server_addr, last_heard.elapsed());
+                        break;
                     }
+                    // It's just a timeout. Keep waiting for replies or the goodbye.
+                    continue;
                 }
 
                 // It's a real error (e.g., connection closed)
-                if is_complete {
-                    // Sender is done and connection closed. This is an expected end.
-                    break;
-                } else {
+                if !(is_complete && e.is_clean_eof()) {
                     // Connection broke unexpectedly
                     eprintln!("Receiver loop error: {}", e);
-                    break;
                 }
+                break;
             }
         }
     }
 
+    let total_sent = packets_sent.load(Ordering::SeqCst);
+    stats.record_unanswered(&server_addr, total_sent.saturating_sub(packets_received));
     latencies
 }
 
@@ -145,14 +171,35 @@ fn client_recv_loop(
 // 在 src/open_loop_client.rs 中
 
 fn init_client(
-    server_addr: SocketAddrV4,
+    server_addr: Endpoint,
     thread_delay: Duration,
     runtime: Duration,
     work: Work,
+    config: ClientConfig,
+    stats: Arc<ClientStats>,
 ) -> JoinHandle<Vec<LatencyRecord>> {
     // ... (stream setup is the same) ...
-    let stream = TcpStream::connect(&server_addr).expect("Couldn't connect to server");
-    stream.set_nodelay(true).expect("set_nodelay call failed");
+    let stream = Stream::connect(&server_addr).expect("Couldn't connect to server");
+    config
+        .socket
+        .apply(&stream)
+        .expect("Failed to set socket options")
+        .log_once("client");
+    // --- SET A READ TIMEOUT ---
+    // This prevents the receiver from blocking forever if the connection is idle
+    stream.set_read_timeout(Some(config.heartbeat_interval))
+        .expect("Failed to set read timeout");
+    let handshake_start = Instant::now();
+    #[cfg(feature = "tls")]
+    let stream = match &config.tls {
+        Some(tls) => crate::tls::connect(stream, tls)
+            .unwrap_or_else(|e| panic!("TLS handshake with {} failed: {}", server_addr, e)),
+        None => stream,
+    };
+    let mut stream = ChunkedTcpStream::new(stream);
+    let params = handshake::connect(&mut stream, config.params)
+        .unwrap_or_else(|e| panic!("handshake with {} failed: {}", server_addr, e));
+    stats.record_handshake(handshake_start.elapsed());
     let thread_start_time = Instant::now();
 
     let sent = Arc::new(AtomicU64::new(0));
@@ -160,39 +207,54 @@ fn init_client(
 
     {
         // ... (sender thread spawn is the same) ...
-        let stream = stream.try_clone().expect("Failed to clone stream");
+        let sender = FramedSender::new(stream.try_clone().expect("Failed to clone stream"))
+            .with_params(params);
         let sent = sent.clone();
         let done = done.clone();
+        let config = config.clone();
         let _ = thread::spawn(move || {
-            client_open_loop(stream, thread_start_time, thread_delay, runtime, sent, work);
+            client_open_loop(
+                sender,
+                thread_start_time,
+                thread_delay,
+                runtime,
+                sent,
+                work,
+                &config,
+            );
             done.store(true, Ordering::SeqCst);
         });
     }
 
-    let recv_handle = {
-        let stream = stream.try_clone().expect("Failed to clone stream");
+    {
+        let receiver = FramedReceiver::new(stream).with_params(params);
         let done = done.clone();
         // --- ADD sent.clone() HERE ---
         let sent_clone = sent.clone();
-        thread::spawn(move || client_recv_loop(stream, done, sent_clone)) // <-- PASS IT HERE
-    };
-
-    recv_handle
+        let dead_after = config.dead_after;
+        thread::spawn(move || {
+            client_recv_loop(receiver, done, sent_clone, stats, server_addr, dead_after) // <-- PASS IT HERE
+        })
+    }
 }
 
 pub fn run(
-    server_addr: SocketAddrV4,
+    server_addr: Endpoint,
     num_threads: usize,
     interarrival: Duration,
     runtime: Duration,
     work: Work,
     outdir: PathBuf,
+    config: ClientConfig,
 ) {
     let thread_delay = interarrival * (num_threads as u32); // 注意: 之前这里乘以 usize 可能导致溢出，改为 u32
+    // A batch goes out as often as its requests would have one by one.
+    let thread_delay = thread_delay * (config.batch_size as u32);
 
     println!("start: thread_delay {:?}", thread_delay);
+    let stats = Arc::new(ClientStats::default());
     let join_handles: Vec<JoinHandle<Vec<LatencyRecord>>> = (0..num_threads)
-        .map(|_| init_client(server_addr, thread_delay, runtime, work))
+        .map(|_| init_client(server_addr.clone(), thread_delay, runtime, work, config.clone(), stats.clone()))
         .collect();
 
     let mut request_latencies: Vec<Vec<LatencyRecord>> = Vec::new();
@@ -200,12 +262,13 @@ pub fn run(
         let thread_latencies = handle.join().unwrap();
         request_latencies.push(thread_latencies);
     }
+    println!("client stats: {}", stats);
 
     let path = outdir;
 
     let mut w = Writer::from_path(&path).expect("Failed to create CSV writer");
 
-    w.write_record(&["idx", "send_us", "recv_us", "server_proc_us", "latency_us"]).unwrap();
+    w.write_record(["idx", "send_us", "recv_us", "server_proc_us", "latency_us"]).unwrap();
 
     for thread_recs in request_latencies {
         for (i, rec) in thread_recs.iter().enumerate() {
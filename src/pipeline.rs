@@ -0,0 +1,268 @@
+//! Pipelined requests: many [`ClientWorkPacket`]s in flight on one connection, with
+//! responses matched back to requests by id in whatever order they arrive. Requests can
+//! go out alone or in batches, and batched replies are handed back one packet at a time.
+
+use crate::{
+    protocol::{FramedReceiver, FramedSender, ProtocolError},
+    serialize::{
+        ClientMessage, ClientWorkPacket, ServerMessage, ServerWorkPacket, UNKNOWN_REQUEST_ID,
+    },
+    transport::{Stream, Transport},
+};
+use std::{
+    collections::{HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// How many answered ids are remembered, to tell a duplicate response from an unknown one.
+const ANSWERED_HISTORY: usize = 4096;
+
+/// Things that can go wrong on a pipelined connection.
+#[derive(Debug)]
+pub enum PipelineError {
+    Protocol(ProtocolError),
+    /// Tried to send a request whose id is already in flight.
+    DuplicateRequest(u64),
+    /// A response for an id that was recently answered already.
+    DuplicateResponse(u64),
+    /// A response for an id that was never sent (or was answered long ago).
+    UnknownResponse(u64),
+}
+
+impl From<ProtocolError> for PipelineError {
+    fn from(value: ProtocolError) -> Self {
+        Self::Protocol(value)
+    }
+}
+
+impl std::fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Protocol(e) => write!(f, "{}", e),
+            Self::DuplicateRequest(id) => write!(f, "request {} is already in flight", id),
+            Self::DuplicateResponse(id) => write!(f, "second response for request {}", id),
+            Self::UnknownResponse(id) => write!(f, "response for unknown request {}", id),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+/// A client connection that keeps track of which requests are still waiting for a response.
+pub struct PipelinedConn<S = Stream> {
+    sender: FramedSender<ClientMessage, S>,
+    receiver: FramedReceiver<ServerMessage, S>,
+    /// Replies already received in a batch but not yet returned by [`Self::recv`].
+    ready: VecDeque<ServerWorkPacket>,
+    outstanding: HashSet<u64>,
+    answered: HashSet<u64>,
+    answered_order: VecDeque<u64>,
+    /// When the last frame of any kind arrived from the server.
+    last_heard: Instant,
+    pings_sent: u64,
+}
+
+impl<S: Transport> PipelinedConn<S> {
+    pub fn new(
+        sender: FramedSender<ClientMessage, S>,
+        receiver: FramedReceiver<ServerMessage, S>,
+    ) -> Self {
+        Self {
+            sender,
+            receiver,
+            ready: VecDeque::new(),
+            outstanding: HashSet::new(),
+            answered: HashSet::new(),
+            answered_order: VecDeque::with_capacity(ANSWERED_HISTORY),
+            last_heard: Instant::now(),
+            pings_sent: 0,
+        }
+    }
+
+    /// Number of requests sent but not yet answered.
+    pub fn in_flight(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn send(&mut self, packet: &ClientWorkPacket) -> Result<(), PipelineError> {
+        if self.outstanding.contains(&packet.id()) {
+            return Err(PipelineError::DuplicateRequest(packet.id()));
+        }
+        self.sender.send_msg(&ClientMessage::Work(*packet))?;
+        self.outstanding.insert(packet.id());
+        Ok(())
+    }
+
+    /// Send several requests in one frame. Nothing is sent if any id is already in flight
+    /// or repeated within the batch.
+    pub fn send_batch(&mut self, packets: Vec<ClientWorkPacket>) -> Result<(), PipelineError> {
+        let mut ids = HashSet::with_capacity(packets.len());
+        for packet in &packets {
+            if self.outstanding.contains(&packet.id()) || !ids.insert(packet.id()) {
+                return Err(PipelineError::DuplicateRequest(packet.id()));
+            }
+        }
+        self.sender.send_msg(&ClientMessage::Batch(packets))?;
+        self.outstanding.extend(ids);
+        Ok(())
+    }
+
+    /// Ask the server to show it is still there. Any frame it sends counts as an answer.
+    pub fn ping(&mut self) -> Result<(), PipelineError> {
+        self.sender
+            .send_msg(&ClientMessage::Ping(self.pings_sent))?;
+        self.pings_sent += 1;
+        Ok(())
+    }
+
+    /// Tell the server no more requests will follow. It answers the ones in flight and then
+    /// says goodbye back, after which [`Self::recv`] returns `None`.
+    pub fn goodbye(&mut self) -> Result<(), PipelineError> {
+        self.sender.send_msg(&ClientMessage::Goodbye)?;
+        Ok(())
+    }
+
+    /// How long since anything arrived from the server.
+    pub fn silent_for(&self) -> Duration {
+        self.last_heard.elapsed()
+    }
+
+    /// Wait for the next response, for any outstanding request. Pongs are absorbed here.
+    /// `None` means the server said goodbye: whatever is still in flight won't be answered.
+    pub fn recv(&mut self) -> Result<Option<ServerWorkPacket>, PipelineError> {
+        let resp = loop {
+            if let Some(resp) = self.ready.pop_front() {
+                break resp;
+            }
+            let msg = self.receiver.recv_msg()?;
+            self.last_heard = Instant::now();
+            if msg == ServerMessage::Goodbye {
+                return Ok(None);
+            }
+            self.ready.extend(msg.into_packets());
+        };
+        let id = resp.client_id();
+        // The server couldn't tell which request this answers, so neither can we.
+        if id == UNKNOWN_REQUEST_ID && resp.failure().is_some() {
+            return Ok(Some(resp));
+        }
+        if !self.outstanding.remove(&id) {
+            return Err(if self.answered.contains(&id) {
+                PipelineError::DuplicateResponse(id)
+            } else {
+                PipelineError::UnknownResponse(id)
+            });
+        }
+
+        if self.answered_order.len() == ANSWERED_HISTORY {
+            let oldest = self.answered_order.pop_front().unwrap();
+            self.answered.remove(&oldest);
+        }
+        self.answered_order.push_back(id);
+        self.answered.insert(id);
+        Ok(Some(resp))
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::{PipelineError, PipelinedConn};
+    use crate::{
+        app::Work,
+        chunked_tcp_stream::stream_pair,
+        config::ServerConfig,
+        protocol::{FramedReceiver, FramedSender},
+        serialize::{ClientMessage, ClientWorkPacket, ServerMessage},
+    };
+    use std::time::Instant;
+
+    #[test]
+    fn out_of_order_responses_match() {
+        let (client, server) = stream_pair();
+        let mut conn = PipelinedConn::new(
+            FramedSender::new(client.try_clone().unwrap()),
+            FramedReceiver::new(client),
+        );
+        let mut server_rx = FramedReceiver::<ClientMessage, _>::new(server.try_clone().unwrap());
+        let mut server_tx = FramedSender::<ServerMessage, _>::new(server);
+
+        for id in 0..4 {
+            conn.send(&ClientWorkPacket::new(id, Work::Immediate))
+                .unwrap();
+        }
+        assert!(matches!(
+            conn.send(&ClientWorkPacket::new(2, Work::Immediate)),
+            Err(PipelineError::DuplicateRequest(2))
+        ));
+        assert_eq!(conn.in_flight(), 4);
+
+        let reqs: Vec<_> = (0..4).map(|_| server_rx.recv_msg().unwrap()).collect();
+        for req in reqs.iter().rev() {
+            for reply in req.do_work(&ServerConfig::default(), Instant::now()) {
+                server_tx.send_msg(&reply).unwrap();
+            }
+        }
+        let ids: Vec<_> = (0..4)
+            .map(|_| conn.recv().unwrap().unwrap().client_id())
+            .collect();
+        assert_eq!(ids, vec![3, 2, 1, 0]);
+        assert_eq!(conn.in_flight(), 0);
+
+        server_tx
+            .send_msg(&reqs[1].do_work(&ServerConfig::default(), Instant::now())[0])
+            .unwrap();
+        server_tx
+            .send_msg(&ServerMessage::Work(
+                ClientWorkPacket::new(99, Work::Immediate).do_work(),
+            ))
+            .unwrap();
+        assert!(matches!(
+            conn.recv(),
+            Err(PipelineError::DuplicateResponse(1))
+        ));
+        assert!(matches!(
+            conn.recv(),
+            Err(PipelineError::UnknownResponse(99))
+        ));
+    }
+
+    #[test]
+    fn batched_replies_unpack() {
+        let (client, server) = stream_pair();
+        let mut conn = PipelinedConn::new(
+            FramedSender::new(client.try_clone().unwrap()),
+            FramedReceiver::new(client),
+        );
+        let mut server_rx = FramedReceiver::<ClientMessage, _>::new(server.try_clone().unwrap());
+        let mut server_tx = FramedSender::<ServerMessage, _>::new(server);
+
+        let batch = (0..3)
+            .map(|id| ClientWorkPacket::new(id, Work::Immediate))
+            .collect();
+        conn.send_batch(batch).unwrap();
+        assert!(matches!(
+            conn.send_batch(vec![
+                ClientWorkPacket::new(5, Work::Immediate),
+                ClientWorkPacket::new(5, Work::Immediate),
+            ]),
+            Err(PipelineError::DuplicateRequest(5))
+        ));
+        assert_eq!(conn.in_flight(), 3);
+
+        let req = server_rx.recv_msg().unwrap();
+        assert_eq!(req.len(), 3);
+        for reply in req.do_work(&ServerConfig::default(), Instant::now()) {
+            server_tx.send_msg(&reply).unwrap();
+        }
+        let ids: Vec<_> = (0..3)
+            .map(|_| conn.recv().unwrap().unwrap().client_id())
+            .collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+        assert_eq!(conn.in_flight(), 0);
+
+        conn.goodbye().unwrap();
+        assert_eq!(server_rx.recv_msg().unwrap(), ClientMessage::Goodbye);
+        server_tx.send_msg(&ServerMessage::Goodbye).unwrap();
+        assert!(conn.recv().unwrap().is_none());
+    }
+}
@@ -1,122 +1,805 @@
 //! protocol.rs
+//!
+//! Messages are framed in one of two ways, chosen per connection during the handshake
+//! (see [`FramingMode`]):
+//!
+//! - chunked (the default): a header chunk carrying the big-endian `u64` length of the
+//!   serialized payload, followed by the payload split into `MSG_SIZE_BYTES` chunks (the
+//!   last one zero-padded).
+//! - compact: an unsigned LEB128 varint length, followed by exactly the payload bytes.
+//!
+//! If the [`Features::CHECKSUM`] feature was agreed, the payload is followed by a
+//! little-endian CRC32C of the payload (inside the padding, for chunked frames). The
+//! length in the header still counts only the payload.
 pub use crate::chunked_tcp_stream::MSG_SIZE_BYTES;
 use crate::{
-    chunked_tcp_stream::ChunkedTcpStream,
-    serialize::{ClientWorkPacket, ServerWorkPacket, MessageTrait},
+    chunked_tcp_stream::{ChunkedTcpStream, RecvChunks},
+    handshake::{Codec, ConnParams, Features, FramingMode},
+    serialize::{ClientWorkPacket, MessageTrait, ServerWorkPacket},
+    transport::{Stream, Transport},
 };
+use std::{io, marker::PhantomData};
 
-pub mod work_request {
-    #[allow(unused_mut)]
-    use super::*;
+/// Frames whose header announces a larger payload are refused, unless the connection was
+/// configured with a different limit.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 1 << 20;
+
+/// Chunk buffers a connection receives into. With read-ahead, one read can take this many
+/// chunks of queued frames.
+pub const RECV_CHUNKS: usize = 32;
+
+/// Things that can go wrong sending or receiving a frame.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The peer closed the connection cleanly, between frames.
+    Eof,
+    /// The peer closed the connection part-way through a frame.
+    Truncated {
+        expected: usize,
+        received: usize,
+    },
+    /// The header announced a payload larger than the configured maximum.
+    Oversize {
+        len: u64,
+        max: usize,
+    },
+    /// The message could not be serialized.
+    Encode(anyhow::Error),
+    /// The payload did not deserialize as the expected message type.
+    Decode(anyhow::Error),
+    /// The payload does not match its CRC32C trailer. The frame was skipped, so the
+    /// connection can keep going.
+    Checksum {
+        expected: u32,
+        actual: u32,
+    },
+    Io(io::Error),
+}
+
+impl ProtocolError {
+    /// Whether this is an orderly disconnect rather than a failure.
+    pub fn is_clean_eof(&self) -> bool {
+        matches!(self, Self::Eof)
+    }
+
+    /// Whether this is a read timeout. Partially received frames are kept, so the receive
+    /// can simply be retried.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Self::Io(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut))
+    }
+
+    /// Whether the peer broke the protocol, as opposed to the connection ending or failing.
+    pub fn is_violation(&self) -> bool {
+        matches!(
+            self,
+            Self::Truncated { .. }
+                | Self::Oversize { .. }
+                | Self::Decode(_)
+                | Self::Checksum { .. }
+        )
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Eof => write!(f, "connection closed"),
+            Self::Truncated { expected, received } => write!(
+                f,
+                "connection closed mid-frame ({} of {} bytes received)",
+                received, expected
+            ),
+            Self::Oversize { len, max } => {
+                write!(f, "frame of {} bytes exceeds the {} byte limit", len, max)
+            }
+            Self::Encode(e) => write!(f, "could not encode message: {}", e),
+            Self::Decode(e) => write!(f, "could not decode message: {}", e),
+            Self::Checksum { expected, actual } => write!(
+                f,
+                "frame checksum mismatch (trailer {:#010x}, payload {:#010x})",
+                expected, actual
+            ),
+            Self::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// A framed connection that sends and receives `T`s over one [`ChunkedTcpStream`].
+pub struct FramedConn<T, S = Stream> {
+    stream: ChunkedTcpStream<S>,
+    params: ConnParams,
+    decoder: FrameDecoder,
+    chunks: RecvChunks,
+    _msg: PhantomData<fn() -> T>,
+}
+
+impl<T: MessageTrait, S: Transport> FramedConn<T, S> {
+    pub fn new(stream: ChunkedTcpStream<S>) -> Self {
+        Self {
+            stream,
+            params: ConnParams::default(),
+            decoder: FrameDecoder::new(ConnParams::default(), DEFAULT_MAX_FRAME_LEN)
+                .with_read_ahead(),
+            chunks: RecvChunks::new(RECV_CHUNKS),
+            _msg: PhantomData,
+        }
+    }
+
+    /// Use the parameters agreed during the handshake.
+    pub fn with_params(mut self, params: ConnParams) -> Self {
+        self.params = params;
+        self.decoder.params = params;
+        self
+    }
+
+    /// Refuse received frames with payloads larger than `max` bytes.
+    pub fn with_max_frame_len(mut self, max: usize) -> Self {
+        self.decoder.max_frame_len = max;
+        self
+    }
+
+    pub fn send_msg(&mut self, msg: &T) -> Result<(), ProtocolError> {
+        send_frame(&mut self.stream, &self.params, msg)
+    }
+
+    pub fn recv_msg(&mut self) -> Result<T, ProtocolError> {
+        recv_frame(&mut self.stream, &mut self.decoder, &mut self.chunks)
+    }
+
+    /// Split into independent send and receive halves, so one thread can send while
+    /// another receives.
+    pub fn split(self) -> Result<FramedHalves<T, S>, ProtocolError> {
+        let recv_stream = self.stream.try_clone()?;
+        let receiver = FramedReceiver {
+            stream: recv_stream,
+            decoder: self.decoder,
+            chunks: self.chunks,
+            _msg: PhantomData,
+        };
+        Ok((
+            FramedSender::new(self.stream).with_params(self.params),
+            receiver,
+        ))
+    }
+}
+
+/// What [`FramedConn::split`] returns.
+pub type FramedHalves<T, S = Stream> = (FramedSender<T, S>, FramedReceiver<T, S>);
+
+/// The sending half of a framed connection.
+pub struct FramedSender<T, S = Stream> {
+    stream: ChunkedTcpStream<S>,
+    params: ConnParams,
+    _msg: PhantomData<fn(T)>,
+}
 
-    pub struct ClientWorkPacketConn {
-        stream: ChunkedTcpStream,
-	// TODO: students can add their own members here
+impl<T: MessageTrait, S: Transport> FramedSender<T, S> {
+    pub fn new(stream: ChunkedTcpStream<S>) -> Self {
+        Self {
+            stream,
+            params: ConnParams::default(),
+            _msg: PhantomData,
+        }
+    }
+
+    /// Use the parameters agreed during the handshake.
+    pub fn with_params(mut self, params: ConnParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    pub fn send_msg(&mut self, msg: &T) -> Result<(), ProtocolError> {
+        send_frame(&mut self.stream, &self.params, msg)
     }
+}
+
+/// The receiving half of a framed connection.
+pub struct FramedReceiver<T, S = Stream> {
+    stream: ChunkedTcpStream<S>,
+    decoder: FrameDecoder,
+    chunks: RecvChunks,
+    _msg: PhantomData<fn() -> T>,
+}
 
-    impl ClientWorkPacketConn {
-        pub fn new(stream: ChunkedTcpStream) -> Self {
-            Self { stream }
+impl<T: MessageTrait, S: Transport> FramedReceiver<T, S> {
+    pub fn new(stream: ChunkedTcpStream<S>) -> Self {
+        Self {
+            stream,
+            decoder: FrameDecoder::new(ConnParams::default(), DEFAULT_MAX_FRAME_LEN)
+                .with_read_ahead(),
+            chunks: RecvChunks::new(RECV_CHUNKS),
+            _msg: PhantomData,
         }
+    }
+
+    /// Use the parameters agreed during the handshake.
+    pub fn with_params(mut self, params: ConnParams) -> Self {
+        self.decoder.params = params;
+        self
+    }
+
+    /// Refuse received frames with payloads larger than `max` bytes.
+    pub fn with_max_frame_len(mut self, max: usize) -> Self {
+        self.decoder.max_frame_len = max;
+        self
+    }
+
+    pub fn recv_msg(&mut self) -> Result<T, ProtocolError> {
+        recv_frame(&mut self.stream, &mut self.decoder, &mut self.chunks)
+    }
+
+    /// How payloads on this connection are serialized.
+    pub fn codec(&self) -> Codec {
+        self.decoder.params.codec
+    }
+
+    /// Receive the next frame's payload without deserializing it, so a caller can still
+    /// answer frames that fail to decode.
+    pub fn recv_payload(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        recv_payload(&mut self.stream, &mut self.decoder, &mut self.chunks)
+    }
+
+    /// Receive the next frame and hand its payload to `f` where it lies in the receive
+    /// buffer, for decoding without copying it out.
+    pub fn recv_with<R>(&mut self, f: impl FnMut(&[u8]) -> R) -> Result<R, ProtocolError> {
+        recv_frame_with(&mut self.stream, &mut self.decoder, &mut self.chunks, f)
+    }
+}
 
-        pub fn send_work_msg(
-            &mut self,
-            work_packet: ClientWorkPacket,
-        ) -> Result<(), anyhow::Error> {
-            let mut data_to_send = Vec::new();
-            work_packet.to_vec(&mut data_to_send)?;
-            let total_len = data_to_send.len() as u64;
+/// Serialize `msg` and append its complete wire representation to `out`.
+pub fn encode_frame<T: MessageTrait>(
+    msg: &T,
+    params: &ConnParams,
+    out: &mut Vec<u8>,
+) -> Result<(), ProtocolError> {
+    let mut payload = Vec::new();
+    msg.encode(params.codec, &mut payload)
+        .map_err(ProtocolError::Encode)?;
+    let len = payload.len();
+    if params.features.contains(Features::CHECKSUM) {
+        let crc = crc32c::crc32c(&payload);
+        payload.extend_from_slice(&crc.to_le_bytes());
+    }
 
+    match params.framing {
+        FramingMode::Chunked => {
             let mut header_chunk = [0u8; MSG_SIZE_BYTES];
-            header_chunk[..8].copy_from_slice(&total_len.to_be_bytes());
-            self.stream.send_msg_chunk(&header_chunk)?;
+            header_chunk[..8].copy_from_slice(&(len as u64).to_be_bytes());
+            out.extend_from_slice(&header_chunk);
+            out.extend_from_slice(&payload);
+            out.resize(out.len() + padded_len(payload.len()) - payload.len(), 0);
+        }
+        FramingMode::Compact => {
+            put_varint(out, len as u64);
+            out.extend_from_slice(&payload);
+        }
+    }
+    Ok(())
+}
 
-            for chunk in data_to_send.chunks(MSG_SIZE_BYTES) {
-                let mut buffer = [0u8; MSG_SIZE_BYTES];
-                buffer[..chunk.len()].copy_from_slice(chunk);
-                self.stream.send_msg_chunk(&buffer)?;
-            }
+/// Chunked payloads are padded up to a whole number of chunks.
+fn padded_len(len: usize) -> usize {
+    len.div_ceil(MSG_SIZE_BYTES) * MSG_SIZE_BYTES
+}
+
+/// Append `v` as an unsigned LEB128 varint.
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+/// Parse an unsigned LEB128 varint from the front of `buf`, returning the value and how
+/// many bytes it took, or `None` if `buf` ends before the varint does.
+fn get_varint(buf: &[u8]) -> Result<Option<(u64, usize)>, ProtocolError> {
+    let mut v = 0u64;
+    for (i, b) in buf.iter().enumerate() {
+        if i == 9 && *b > 1 {
+            return Err(ProtocolError::Decode(anyhow::anyhow!(
+                "frame length varint overflows u64"
+            )));
+        }
+        v |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return Ok(Some((v, i + 1)));
+        }
+    }
+    Ok(None)
+}
+
+/// Reassembles frames from bytes as they arrive, in whatever pieces they arrive in.
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    params: ConnParams,
+    max_frame_len: usize,
+    read_ahead: bool,
+}
+
+impl FrameDecoder {
+    pub fn new(params: ConnParams, max_frame_len: usize) -> Self {
+        Self {
+            buf: Vec::with_capacity(MSG_SIZE_BYTES),
+            params,
+            max_frame_len,
+            read_ahead: false,
+        }
+    }
 
-            Ok(())
+    /// Let reads run on into frames queued behind the current one. Only for a decoder that
+    /// stays with its stream for good, or the bytes it holds would be lost.
+    pub fn with_read_ahead(mut self) -> Self {
+        self.read_ahead = true;
+        self
+    }
+
+    /// How many more bytes complete the frame at the front of the buffer. Reading no more
+    /// than this never consumes bytes belonging to the next frame.
+    pub fn wanted(&self) -> usize {
+        match self.frame_end() {
+            Some(end) => end.saturating_sub(self.buf.len()).max(1),
+            None => match self.params.framing {
+                FramingMode::Chunked => MSG_SIZE_BYTES - self.buf.len(),
+                // The length varint is read a byte at a time, since we can't tell where it
+                // ends until we see it.
+                FramingMode::Compact => 1,
+            },
         }
+    }
+
+    /// How much the next read into buffers of `capacity` bytes should take, as
+    /// `(at_least, at_most)`: enough to finish the frame at the front, and with read-ahead
+    /// whatever else fits.
+    pub fn read_bounds(&self, capacity: usize) -> (usize, usize) {
+        let at_least = self.wanted().min(capacity);
+        (at_least, if self.read_ahead { capacity } else { at_least })
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Take the payload of the next complete frame, if there is one.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, ProtocolError> {
+        self.next_frame_with(<[u8]>::to_vec)
+    }
 
-        pub fn recv_work_msg(&mut self) -> Result<ClientWorkPacket, anyhow::Error> {
-            let mut first_chunk = [0u8; MSG_SIZE_BYTES];
-            self.stream.recv_msg_chunk(&mut first_chunk)?;
-            let total_len = u64::from_be_bytes(first_chunk[..8].try_into()?) as usize;
+    /// Hand the payload of the next complete frame, if there is one, to `f` without
+    /// copying it, then drop the frame.
+    pub fn next_frame_with<R>(&mut self, f: impl FnOnce(&[u8]) -> R) -> Result<Option<R>, ProtocolError> {
+        let Some((header_len, len)) = self.header()? else {
+            return Ok(None);
+        };
+        if len > self.max_frame_len as u64 {
+            return Err(ProtocolError::Oversize {
+                len,
+                max: self.max_frame_len,
+            });
+        }
 
-            let mut received_data = Vec::with_capacity(total_len);
-            let first_data_len = (total_len).min(MSG_SIZE_BYTES - 8);
-            received_data.extend_from_slice(&first_chunk[8..8 + first_data_len]);
+        let len = len as usize;
+        let end = header_len + self.body_len(len);
+        if self.buf.len() < end {
+            return Ok(None);
+        }
+        let payload = &self.buf[header_len..header_len + len];
+        let checksums = self
+            .checksum_trailer(header_len + len)
+            .map(|expected| (expected, crc32c::crc32c(payload)));
+        let got = match checksums {
+            Some((expected, actual)) if actual != expected => {
+                Err(ProtocolError::Checksum { expected, actual })
+            }
+            _ => Ok(Some(f(payload))),
+        };
+        self.buf.drain(..end);
+        got
+    }
 
-            while received_data.len() < total_len {
-                let mut chunk_buf = [0u8; MSG_SIZE_BYTES];
-                self.stream.recv_msg_chunk(&mut chunk_buf)?;
+    /// The error to report when the stream ends here.
+    pub fn eof_error(&self) -> ProtocolError {
+        if self.buf.is_empty() {
+            ProtocolError::Eof
+        } else {
+            ProtocolError::Truncated {
+                expected: self.frame_end().unwrap_or(self.buf.len() + self.wanted()),
+                received: self.buf.len(),
+            }
+        }
+    }
 
-                let bytes_to_take = (total_len - received_data.len()).min(MSG_SIZE_BYTES);
-                received_data.extend_from_slice(&chunk_buf[..bytes_to_take]);
+    /// The header at the front of the buffer, as `(header_len, payload_len)`, once it has
+    /// fully arrived.
+    fn header(&self) -> Result<Option<(usize, u64)>, ProtocolError> {
+        match self.params.framing {
+            FramingMode::Chunked => Ok((self.buf.len() >= MSG_SIZE_BYTES).then(|| {
+                let len = u64::from_be_bytes(self.buf[..8].try_into().unwrap());
+                (MSG_SIZE_BYTES, len)
+            })),
+            FramingMode::Compact => {
+                Ok(get_varint(&self.buf)?.map(|(len, header_len)| (header_len, len)))
             }
+        }
+    }
 
-            let packet = ClientWorkPacket::from_bytes(&received_data)?;
-            Ok(packet)
+    /// Bytes on the wire between the header and the end of a frame with a `len` byte payload.
+    fn body_len(&self, len: usize) -> usize {
+        let len = len + self.trailer_len();
+        match self.params.framing {
+            FramingMode::Chunked => padded_len(len),
+            FramingMode::Compact => len,
         }
     }
 
-    // TODO: Students can add helper functions here.
+    fn trailer_len(&self) -> usize {
+        if self.params.features.contains(Features::CHECKSUM) {
+            4
+        } else {
+            0
+        }
+    }
+
+    /// The CRC32C trailer starting at `at`, if checksums are on.
+    fn checksum_trailer(&self, at: usize) -> Option<u32> {
+        let bytes = self.buf.get(at..at + self.trailer_len())?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    fn frame_end(&self) -> Option<usize> {
+        let (header_len, len) = self.header().ok()??;
+        let len = usize::try_from(len).ok()?.min(self.max_frame_len);
+        Some(header_len + self.body_len(len))
+    }
+}
+
+pub(crate) fn send_frame<T: MessageTrait, S: Transport>(
+    stream: &mut ChunkedTcpStream<S>,
+    params: &ConnParams,
+    msg: &T,
+) -> Result<(), ProtocolError> {
+    let mut data_to_send = Vec::new();
+    encode_frame(msg, params, &mut data_to_send)?;
+    let chunks: Vec<&[u8]> = data_to_send.chunks(MSG_SIZE_BYTES).collect();
+    stream.send_msg_chunks(&chunks)?;
+    Ok(())
+}
+
+pub(crate) fn recv_frame<T: MessageTrait, S: Transport>(
+    stream: &mut ChunkedTcpStream<S>,
+    decoder: &mut FrameDecoder,
+    chunks: &mut RecvChunks,
+) -> Result<T, ProtocolError> {
+    let codec = decoder.params.codec;
+    recv_frame_with(stream, decoder, chunks, |payload| T::decode(codec, payload))?
+        .map_err(ProtocolError::Decode)
+}
+
+fn recv_payload<S: Transport>(
+    stream: &mut ChunkedTcpStream<S>,
+    decoder: &mut FrameDecoder,
+    chunks: &mut RecvChunks,
+) -> Result<Vec<u8>, ProtocolError> {
+    recv_frame_with(stream, decoder, chunks, <[u8]>::to_vec)
+}
+
+/// Receive until a frame is complete, and hand its payload to `f` in place.
+fn recv_frame_with<R, S: Transport>(
+    stream: &mut ChunkedTcpStream<S>,
+    decoder: &mut FrameDecoder,
+    chunks: &mut RecvChunks,
+    mut f: impl FnMut(&[u8]) -> R,
+) -> Result<R, ProtocolError> {
+    loop {
+        if let Some(got) = decoder.next_frame_with(&mut f)? {
+            return Ok(got);
+        }
+
+        let (at_least, at_most) = decoder.read_bounds(chunks.capacity());
+        let (n, result) = stream.recv_msg_chunks(chunks, at_least, at_most);
+        // Keep what arrived before a timeout, so a retry picks up where this left off.
+        decoder.feed(chunks.filled(n));
+        result?;
+        if n < at_least {
+            return Err(decoder.eof_error());
+        }
+    }
+}
+
+pub mod work_request {
+    use super::*;
+
+    /// Carries [`ClientWorkPacket`]s from the client to the server.
+    pub type ClientWorkPacketConn = FramedConn<ClientWorkPacket>;
 }
 
 pub mod work_response {
     use super::*;
 
-    pub struct ServerWorkPacketConn {
-        stream: ChunkedTcpStream,
-	// TODO: students can add their own members here
+    /// Carries [`ServerWorkPacket`]s from the server back to the client.
+    pub type ServerWorkPacketConn = FramedConn<ServerWorkPacket>;
+}
+
+#[cfg(test)]
+mod t {
+    use super::{
+        encode_frame, FrameDecoder, FramedConn, FramedReceiver, FramedSender, ProtocolError,
+        MSG_SIZE_BYTES,
+    };
+    use crate::{
+        app::Work,
+        chunked_tcp_stream::{stream_pair, ChunkedTcpStream},
+        transport::Stream,
+        handshake::{Codec, ConnParams, Features, FramingMode},
+        serialize::{ClientWorkPacket, ServerMessage, ServerMessageRef, ServerWorkPacket},
+    };
+    use std::{io::Write, os::unix::net::UnixStream, time::Duration};
+
+    fn params(framing: FramingMode) -> ConnParams {
+        ConnParams {
+            framing,
+            ..Default::default()
+        }
     }
 
-    impl ServerWorkPacketConn {
-        pub fn send_work_msg(&mut self, packet: ServerWorkPacket) -> Result<(), anyhow::Error> {
-            let mut data_to_send = Vec::new();
-            packet.to_vec(&mut data_to_send)?;
-            let total_len = data_to_send.len() as u64;
+    #[test]
+    fn framed_conn_bounce() {
+        let (client, server) = stream_pair();
+        let mut client = FramedConn::<ClientWorkPacket, _>::new(client);
+        let mut server = FramedConn::<ClientWorkPacket, _>::new(server);
 
-            let mut header_chunk = [0u8; MSG_SIZE_BYTES];
-            header_chunk[..8].copy_from_slice(&total_len.to_be_bytes());
-            self.stream.send_msg_chunk(&header_chunk)?;
+        let req = ClientWorkPacket::new(7, Work::Const(3));
+        client.send_msg(&req).expect("send request");
+        assert_eq!(server.recv_msg().expect("recv request"), req);
+    }
+
+    #[test]
+    fn split_halves_multi_chunk() {
+        let (client, server) = stream_pair();
+        let mut client_rx = FramedReceiver::<ServerWorkPacket, _>::new(client.try_clone().unwrap());
+        let mut client_tx = FramedSender::<ClientWorkPacket, _>::new(client);
+        let (_, mut server_rx) = FramedConn::<ClientWorkPacket, _>::new(server.try_clone().unwrap())
+            .split()
+            .expect("split server");
+        let mut server_tx = FramedSender::<ServerWorkPacket, _>::new(server);
 
-            for chunk in data_to_send.chunks(MSG_SIZE_BYTES) {
-                let mut buffer = [0u8; MSG_SIZE_BYTES];
-                buffer[..chunk.len()].copy_from_slice(chunk);
-                self.stream.send_msg_chunk(&buffer)?;
+        let sender = std::thread::spawn(move || {
+            for id in 0..16 {
+                client_tx
+                    .send_msg(&ClientWorkPacket::new(id, Work::Payload))
+                    .expect("send request");
             }
+        });
 
-            Ok(())
+        for _ in 0..16 {
+            let req = server_rx.recv_msg().expect("recv request");
+            server_tx.send_msg(&req.do_work()).expect("send response");
         }
 
-        pub fn recv_work_msg(&mut self) -> Result<ServerWorkPacket, anyhow::Error> {
-            let mut first_chunk = [0u8; MSG_SIZE_BYTES];
-            self.stream.recv_msg_chunk(&mut first_chunk)?;
-            let total_len = u64::from_be_bytes(first_chunk[..8].try_into()?) as usize;
+        let ids: Vec<_> = (0..16)
+            .map(|_| client_rx.recv_msg().expect("recv response").client_id())
+            .collect();
+        sender.join().unwrap();
+        assert_eq!(ids, (0..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn decoder_reassembles_byte_at_a_time() {
+        for framing in [FramingMode::Chunked, FramingMode::Compact] {
+            let reqs: Vec<_> = (0..3)
+                .map(|id| ClientWorkPacket::new(id, Work::Immediate))
+                .collect();
+            let mut wire = Vec::new();
+            for r in &reqs {
+                encode_frame(r, &params(framing), &mut wire).unwrap();
+            }
+
+            let mut decoder = FrameDecoder::new(params(framing), 1024);
+            let mut got = Vec::new();
+            for b in wire {
+                decoder.feed(&[b]);
+                while let Some(payload) = decoder.next_frame().unwrap() {
+                    got.push(
+                        <ClientWorkPacket as crate::serialize::MessageTrait>::from_bytes(&payload)
+                            .unwrap(),
+                    );
+                }
+            }
+            assert_eq!(got, reqs);
+            assert!(decoder.eof_error().is_clean_eof());
+        }
+    }
+
+    #[test]
+    fn compact_framing_has_no_padding() {
+        let req = ClientWorkPacket::new(1, Work::Immediate);
+        let mut payload = Vec::new();
+        crate::serialize::MessageTrait::to_vec(&req, &mut payload).unwrap();
+
+        let mut wire = Vec::new();
+        encode_frame(&req, &params(FramingMode::Compact), &mut wire).unwrap();
+        assert_eq!(wire.len(), 1 + payload.len());
+
+        wire.clear();
+        encode_frame(&req, &params(FramingMode::Chunked), &mut wire).unwrap();
+        assert_eq!(wire.len(), 2 * MSG_SIZE_BYTES);
+    }
 
-            let mut received_data = Vec::with_capacity(total_len);
-            let first_data_len = (total_len).min(MSG_SIZE_BYTES - 8);
-            received_data.extend_from_slice(&first_chunk[8..8 + first_data_len]);
+    #[test]
+    fn compact_conn_multi_chunk() {
+        let (client, server) = stream_pair();
+        let compact = params(FramingMode::Compact);
+        let mut client = FramedConn::<ServerWorkPacket, _>::new(client).with_params(compact);
+        let mut server = FramedConn::<ServerWorkPacket, _>::new(server).with_params(compact);
 
-            while received_data.len() < total_len {
-                let mut chunk_buf = [0u8; MSG_SIZE_BYTES];
-                self.stream.recv_msg_chunk(&mut chunk_buf)?;
+        // Payload responses span several chunks, and need a multi-byte varint.
+        let resps: Vec<_> = (0..8)
+            .map(|id| ClientWorkPacket::new(id, Work::Payload).do_work())
+            .collect();
+        for r in &resps {
+            server.send_msg(r).expect("send response");
+        }
+        for r in &resps {
+            assert_eq!(&client.recv_msg().expect("recv response"), r);
+        }
+    }
 
-                let bytes_to_take = (total_len - received_data.len()).min(MSG_SIZE_BYTES);
-                received_data.extend_from_slice(&chunk_buf[..bytes_to_take]);
+    #[test]
+    fn receiver_reads_ahead_queued_frames() {
+        for framing in [FramingMode::Chunked, FramingMode::Compact] {
+            let (mut client, server) = stream_pair();
+            let reqs: Vec<_> = (0..3)
+                .map(|id| ClientWorkPacket::new(id, Work::Payload))
+                .collect();
+            let mut wire = Vec::new();
+            for r in &reqs {
+                encode_frame(r, &params(framing), &mut wire).unwrap();
             }
+            let first_len = wire.len() / reqs.len();
+            let chunks: Vec<&[u8]> = wire.chunks(MSG_SIZE_BYTES).collect();
+            client.send_msg_chunks(&chunks).unwrap();
+            drop(client);
 
-            let packet = ServerWorkPacket::from_bytes(&received_data)?;
-            Ok(packet)
+            let mut server = FramedReceiver::<ClientWorkPacket, _>::new(server).with_params(params(framing));
+            assert_eq!(server.recv_msg().unwrap(), reqs[0]);
+            // The frames behind the first came in with it.
+            assert_eq!(server.decoder.buf.len(), wire.len() - first_len);
+            for r in &reqs[1..] {
+                assert_eq!(&server.recv_msg().unwrap(), r);
+            }
+            assert!(matches!(server.recv_msg(), Err(ProtocolError::Eof)));
         }
+    }
+
+    #[test]
+    fn fixed_replies_decode_in_the_receive_buffer() {
+        let params = ConnParams {
+            codec: Codec::Fixed,
+            ..Default::default()
+        };
+        let (client, server) = stream_pair();
+        let mut client = FramedSender::<ServerMessage, _>::new(client).with_params(params);
+        let mut server = FramedReceiver::<ServerMessage, _>::new(server).with_params(params);
+
+        let reply = ServerMessage::Work(ClientWorkPacket::new(4, Work::Payload).do_work());
+        client.send_msg(&reply).unwrap();
+        let got = server
+            .recv_with(|frame| {
+                let msg = ServerMessageRef::decode_fixed(frame).unwrap();
+                let ServerMessageRef::Work(packet) = msg else {
+                    panic!("expected work");
+                };
+                assert!(frame.as_ptr_range().contains(&packet.payload().unwrap().as_ptr()));
+                msg.to_owned()
+            })
+            .unwrap();
+        assert_eq!(got, reply);
+    }
+
+    #[test]
+    fn partial_frame_survives_read_timeout() {
+        let (server, mut client) = UnixStream::pair().unwrap();
+        let server = Stream::from(server);
+        server.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let mut server = FramedConn::<ClientWorkPacket, _>::new(ChunkedTcpStream::new(server));
 
-        pub fn new(stream: ChunkedTcpStream) -> Self {
-            Self { stream }
+        let req = ClientWorkPacket::new(9, Work::Payload);
+        let mut wire = Vec::new();
+        encode_frame(&req, &params(FramingMode::Chunked), &mut wire).unwrap();
+        client.write_all(&wire[..64]).unwrap();
+        assert!(server.recv_msg().unwrap_err().is_timeout());
+
+        client.write_all(&wire[64..]).unwrap();
+        assert_eq!(server.recv_msg().unwrap(), req);
+    }
+
+    #[test]
+    fn checksum_detects_corruption() {
+        for framing in [FramingMode::Chunked, FramingMode::Compact] {
+            let params = ConnParams {
+                framing,
+                features: Features::CHECKSUM,
+                ..Default::default()
+            };
+            let req = ClientWorkPacket::new(3, Work::Const(10));
+            let mut wire = Vec::new();
+            encode_frame(&req, &params, &mut wire).unwrap();
+            let first_len = wire.len();
+            encode_frame(&req, &params, &mut wire).unwrap();
+
+            // Flip a bit in the first frame's payload.
+            let payload_at = match framing {
+                FramingMode::Chunked => MSG_SIZE_BYTES,
+                FramingMode::Compact => 1,
+            };
+            wire[payload_at + 2] ^= 0x10;
+
+            let mut decoder = FrameDecoder::new(params, 1024);
+            decoder.feed(&wire);
+            assert!(matches!(
+                decoder.next_frame(),
+                Err(ProtocolError::Checksum { .. })
+            ));
+            // The bad frame was skipped, and the next one is intact.
+            let payload = decoder.next_frame().unwrap().expect("second frame");
+            assert_eq!(
+                <ClientWorkPacket as crate::serialize::MessageTrait>::from_bytes(&payload).unwrap(),
+                req
+            );
+            assert!(decoder.eof_error().is_clean_eof());
+            assert!(first_len < wire.len());
         }
     }
 
-    // TODO: Students can add helper functions here.
+    #[test]
+    fn oversize_frame_rejected() {
+        let (mut client, server) = stream_pair();
+        let mut header = [0u8; MSG_SIZE_BYTES];
+        header[..8].copy_from_slice(&u64::MAX.to_be_bytes());
+        client.send_msg_chunk(&header).unwrap();
+
+        let mut server = FramedReceiver::<ClientWorkPacket, _>::new(server).with_max_frame_len(4096);
+        assert!(matches!(
+            server.recv_msg(),
+            Err(ProtocolError::Oversize {
+                len: u64::MAX,
+                max: 4096
+            })
+        ));
+    }
+
+    #[test]
+    fn clean_eof_and_truncation() {
+        let (client, server) = stream_pair();
+        drop(client);
+        let mut server = FramedReceiver::<ClientWorkPacket, _>::new(server);
+        assert!(matches!(server.recv_msg(), Err(ProtocolError::Eof)));
+
+        let (mut client, server) = stream_pair();
+        let mut wire = Vec::new();
+        encode_frame(
+            &ClientWorkPacket::new(0, Work::Immediate),
+            &ConnParams::default(),
+            &mut wire,
+        )
+        .unwrap();
+        client.send_msg_chunk(&wire[..MSG_SIZE_BYTES]).unwrap();
+        client
+            .send_msg_chunk(&wire[MSG_SIZE_BYTES..MSG_SIZE_BYTES + 5])
+            .unwrap();
+        drop(client);
+        let mut server = FramedReceiver::<ClientWorkPacket, _>::new(server);
+        assert!(matches!(
+            server.recv_msg(),
+            Err(ProtocolError::Truncated { expected, received }) if expected == wire.len() && received == MSG_SIZE_BYTES + 5
+        ));
+    }
 }
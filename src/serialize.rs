@@ -1,11 +1,34 @@
 //! Message serialization types and functions.
+//!
+//! Messages can be serialized with any [`Codec`] agreed in the handshake. The
+//! [`Codec::Fixed`] layouts, all little-endian:
+//!
+//! - work: kind `u32` (0 immediate, 1 const, 2 payload, 3 poisson, 4 busytime,
+//!   5 busywork), reserved `u32`, amount `u64`; 16 bytes.
+//! - [`ClientWorkPacket`]: id `u64`, timestamp `u64`, work; 32 bytes.
+//! - [`ServerWorkPacket`]: client id `u64`, client send time `u64`, server processing
+//!   time `u64`, status `u8`, failure code `u8` (`0xff` for none), reserved `u16`, payload
+//!   length `u32` (`u32::MAX` for none), failure reason length `u32`, reserved `u32`; then
+//!   the payload and the UTF-8 reason.
+//! - [`ClientMessage`] and [`ServerMessage`]: variant `u32` (in declaration order), packet
+//!   count `u32`, then the packets, or the `u64` of a ping or pong.
+//!
+//! [`ServerMessageRef`] decodes a fixed-layout reply where it lies, borrowing payloads and
+//! failure reasons from the frame; with [`crate::protocol::FramedReceiver::recv_with`]
+//! nothing is copied out of the receive buffer. Decoding into an owned message copies
+//! just those two.
 
-use crate::{app::Work, get_current_time_micros};
+use crate::{app::Work, config::ServerConfig, get_current_time_micros, handshake::Codec};
+use anyhow::{anyhow, bail};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::num::NonZeroU64;
+use std::panic::{self, AssertUnwindSafe};
 use std::time::Instant;
 
+/// Stands in for the request id in failures whose request could not be read.
+pub const UNKNOWN_REQUEST_ID: u64 = u64::MAX;
+
 #[derive(Debug, Clone)]
 pub struct LatencyRecord {
     pub latency: u64,
@@ -44,8 +67,88 @@ impl ClientWorkPacket {
             client_id: self.id,
             client_send_time: self.timestamp,
             payload,
+            failure: None,
+        }
+    }
+
+    /// Like [`Self::do_work`], but turns a panic or a missed `deadline` into a failure
+    /// instead. A request whose deadline has passed before it starts is not run at all.
+    pub fn try_work(&self, deadline: Option<Instant>) -> ServerWorkPacket {
+        let late = |now: Instant| deadline.is_some_and(|d| now > d);
+        if late(Instant::now()) {
+            return self.fail(
+                FailureCode::TimedOut,
+                "deadline passed before the work started",
+            );
+        }
+
+        let start = Instant::now();
+        match panic::catch_unwind(AssertUnwindSafe(|| self.do_work())) {
+            Ok(_) if late(Instant::now()) => self.fail(
+                FailureCode::TimedOut,
+                format!(
+                    "work took {}us and missed its deadline",
+                    start.elapsed().as_micros()
+                ),
+            ),
+            Ok(reply) => reply,
+            Err(cause) => {
+                let reason = cause
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| cause.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".into());
+                self.fail(FailureCode::Panicked, reason)
+            }
         }
     }
+
+    /// A failed reply to this request.
+    pub fn fail(&self, code: FailureCode, reason: impl Into<String>) -> ServerWorkPacket {
+        ServerWorkPacket::failed(self.id, self.timestamp, code, reason)
+    }
+}
+
+/// Why a request failed.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FailureCode {
+    /// The request frame arrived intact but could not be deserialized.
+    Undecodable = 0,
+    /// The server refused the request, e.g. because its batch was too large.
+    Rejected = 1,
+    /// The request missed the server's deadline.
+    TimedOut = 2,
+    /// The work panicked.
+    Panicked = 3,
+}
+
+impl FailureCode {
+    pub const ALL: [FailureCode; 4] = [
+        Self::Undecodable,
+        Self::Rejected,
+        Self::TimedOut,
+        Self::Panicked,
+    ];
+}
+
+impl std::fmt::Display for FailureCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Undecodable => "undecodable",
+            Self::Rejected => "rejected",
+            Self::TimedOut => "timed_out",
+            Self::Panicked => "panicked",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Carried by every [`ServerWorkStatus::Failed`] response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Failure {
+    pub code: FailureCode,
+    pub reason: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -55,6 +158,8 @@ pub struct ServerWorkPacket {
     client_id: u64,
     client_send_time: u64,
     payload: Option<Vec<u8>>,
+    /// Set exactly when `status` is [`ServerWorkStatus::Failed`].
+    failure: Option<Failure>,
 }
 
 #[repr(u8)]
@@ -65,6 +170,34 @@ pub enum ServerWorkStatus {
 }
 
 impl ServerWorkPacket {
+    pub fn failed(
+        client_id: u64,
+        client_send_time: u64,
+        code: FailureCode,
+        reason: impl Into<String>,
+    ) -> Self {
+        Self {
+            status: ServerWorkStatus::Failed,
+            server_processing_time: 0,
+            client_id,
+            client_send_time,
+            payload: None,
+            failure: Some(Failure {
+                code,
+                reason: reason.into(),
+            }),
+        }
+    }
+
+    pub fn status(&self) -> ServerWorkStatus {
+        self.status
+    }
+
+    /// What went wrong, for a failed response.
+    pub fn failure(&self) -> Option<&Failure> {
+        self.failure.as_ref()
+    }
+
     pub fn client_id(&self) -> u64 {
         self.client_id
     }
@@ -99,6 +232,122 @@ impl ServerWorkPacket {
     }
 }
 
+/// Everything a client can send in one frame, after the handshake.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ClientMessage {
+    Work(ClientWorkPacket),
+    /// Many requests sharing one frame.
+    Batch(Vec<ClientWorkPacket>),
+    /// Liveness probe. The server answers with a [`ServerMessage::Pong`] carrying the same
+    /// value.
+    Ping(u64),
+    /// No more requests will follow. The server answers everything it has received, then
+    /// replies with [`ServerMessage::Goodbye`] and closes.
+    Goodbye,
+}
+
+impl ClientMessage {
+    /// Number of requests carried.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Work(_) => 1,
+            Self::Batch(packets) => packets.len(),
+            Self::Ping(_) | Self::Goodbye => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Run every request carried, which the server received at `received`, and build the
+    /// replies. A batch gets one batched reply, or one [`ServerMessage::Work`] per request
+    /// when `config.split_batches` is set. Requests that fail get failed replies.
+    pub fn do_work(&self, config: &ServerConfig, received: Instant) -> Vec<ServerMessage> {
+        let deadline = config.request_timeout.map(|timeout| received + timeout);
+        let run = |p: &ClientWorkPacket| {
+            if self.len() > config.max_batch_len {
+                p.fail(
+                    FailureCode::Rejected,
+                    format!(
+                        "batch of {} exceeds the limit of {}",
+                        self.len(),
+                        config.max_batch_len
+                    ),
+                )
+            } else {
+                p.try_work(deadline)
+            }
+        };
+        match self {
+            Self::Work(packet) => vec![ServerMessage::Work(packet.try_work(deadline))],
+            Self::Batch(packets) if config.split_batches => packets
+                .iter()
+                .map(|p| ServerMessage::Work(run(p)))
+                .collect(),
+            Self::Batch(packets) => vec![ServerMessage::Batch(packets.iter().map(run).collect())],
+            Self::Ping(seq) => vec![ServerMessage::Pong(*seq)],
+            Self::Goodbye => Vec::new(),
+        }
+    }
+
+    /// The failed reply to a frame, serialized with `codec`, that arrived intact but did not
+    /// deserialize. The request id is recovered if the frame starts like a single request,
+    /// otherwise it is [`UNKNOWN_REQUEST_ID`].
+    pub fn undecodable(codec: Codec, payload: &[u8], err: &anyhow::Error) -> ServerMessage {
+        let id = match codec {
+            // bincode writes `Work(packet)` as a u32 variant index (0), then the packet, whose
+            // first field is its id.
+            Codec::Bincode => match bincode::deserialize::<(u32, u64)>(payload) {
+                Ok((0, id)) => Some(id),
+                _ => None,
+            },
+            Codec::Json => serde_json::from_slice::<serde_json::Value>(payload)
+                .ok()
+                .and_then(|v| v["Work"]["id"].as_u64()),
+            Codec::Fixed => {
+                let mut r = FixedReader(payload);
+                match (r.u32(), r.u32(), r.u64()) {
+                    (Ok(0), Ok(1), Ok(id)) => Some(id),
+                    _ => None,
+                }
+            }
+        }
+        .unwrap_or(UNKNOWN_REQUEST_ID);
+        ServerMessage::Work(ServerWorkPacket::failed(
+            id,
+            0,
+            FailureCode::Undecodable,
+            err.to_string(),
+        ))
+    }
+}
+
+/// Everything a server can send in one frame, after the handshake.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ServerMessage {
+    Work(ServerWorkPacket),
+    /// Replies to a [`ClientMessage::Batch`], in request order.
+    Batch(Vec<ServerWorkPacket>),
+    /// Answer to a [`ClientMessage::Ping`].
+    Pong(u64),
+    /// Nothing more will be sent on this connection. Every request the server received has
+    /// been answered by then. Sent in reply to [`ClientMessage::Goodbye`], or unprompted
+    /// when the server is shutting down.
+    Goodbye,
+}
+
+impl ServerMessage {
+    /// The work replies carried, in order.
+    pub fn into_packets(self) -> Vec<ServerWorkPacket> {
+        match self {
+            Self::Work(packet) => vec![packet],
+            Self::Batch(packets) => packets,
+            Self::Pong(_) | Self::Goodbye => Vec::new(),
+        }
+    }
+}
+
 pub fn generate_random_work() -> Work {
     let mut rng = rand::thread_rng();
     match rng.gen_range(0..6) {
@@ -127,16 +376,413 @@ pub trait MessageTrait: Serialize + for<'a> Deserialize<'a> {
         let packet: Self = bincode::deserialize_from(buf)?;
         Ok(packet)
     }
+
+    /// Serialize with `codec`, appending to `buf`.
+    fn encode(&self, codec: Codec, buf: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        match codec {
+            Codec::Bincode => self.to_vec(buf),
+            Codec::Json => Ok(serde_json::to_writer(buf, self)?),
+            Codec::Fixed => self.put_fixed(buf),
+        }
+    }
+
+    /// Deserialize a message `codec` serialized.
+    fn decode(codec: Codec, buf: &[u8]) -> Result<Self, anyhow::Error> {
+        match codec {
+            Codec::Bincode => Self::from_bytes(buf),
+            Codec::Json => Ok(serde_json::from_slice(buf)?),
+            Codec::Fixed => Self::get_fixed(buf),
+        }
+    }
+
+    /// Append the [`Codec::Fixed`] layout. Only messages sent after the handshake have one.
+    fn put_fixed(&self, _buf: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        Err(no_fixed_layout::<Self>())
+    }
+
+    fn get_fixed(_buf: &[u8]) -> Result<Self, anyhow::Error> {
+        Err(no_fixed_layout::<Self>())
+    }
+}
+
+fn no_fixed_layout<T>() -> anyhow::Error {
+    anyhow!("{} has no fixed layout", std::any::type_name::<T>())
+}
+
+/// Reads [`Codec::Fixed`] fields in place, front to back.
+struct FixedReader<'a>(&'a [u8]);
+
+impl<'a> FixedReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], anyhow::Error> {
+        if self.0.len() < len {
+            bail!("message ends {} bytes early", len - self.0.len());
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, anyhow::Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, anyhow::Error> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, anyhow::Error> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, anyhow::Error> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// How many of `size` byte items could still follow; bounds counts read off the wire.
+    fn room_for(&self, count: u32, size: usize) -> Result<usize, anyhow::Error> {
+        let count = count as usize;
+        if count > self.0.len() / size {
+            bail!("{} items can't fit in the {} bytes left", count, self.0.len());
+        }
+        Ok(count)
+    }
+
+    /// Fail if anything follows the message.
+    fn finish(self) -> Result<(), anyhow::Error> {
+        if !self.0.is_empty() {
+            bail!("{} bytes after the end of the message", self.0.len());
+        }
+        Ok(())
+    }
+}
+
+const FIXED_WORK_LEN: usize = 16;
+const FIXED_REQUEST_LEN: usize = 16 + FIXED_WORK_LEN;
+const FIXED_REPLY_HEADER_LEN: usize = 40;
+const NO_FAILURE: u8 = 0xff;
+const NO_PAYLOAD: u32 = u32::MAX;
+
+fn put_work(work: Work, buf: &mut Vec<u8>) {
+    let (kind, amount) = match work {
+        Work::Immediate => (0u32, 0),
+        Work::Const(amount) => (1, amount),
+        Work::Payload => (2, 0),
+        Work::Poisson(amount) => (3, amount.get()),
+        Work::BusyTimeConst(amount) => (4, amount),
+        Work::BusyWorkConst(amount) => (5, amount),
+    };
+    buf.extend_from_slice(&kind.to_le_bytes());
+    buf.extend_from_slice(&0u32.to_le_bytes());
+    buf.extend_from_slice(&amount.to_le_bytes());
+}
+
+fn get_work(r: &mut FixedReader<'_>) -> Result<Work, anyhow::Error> {
+    let kind = r.u32()?;
+    r.u32()?;
+    let amount = r.u64()?;
+    Ok(match kind {
+        0 => Work::Immediate,
+        1 => Work::Const(amount),
+        2 => Work::Payload,
+        3 => Work::Poisson(NonZeroU64::new(amount).ok_or_else(|| anyhow!("zero poisson work"))?),
+        4 => Work::BusyTimeConst(amount),
+        5 => Work::BusyWorkConst(amount),
+        _ => bail!("unknown work kind {}", kind),
+    })
+}
+
+impl ClientWorkPacket {
+    fn put(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.id.to_le_bytes());
+        buf.extend_from_slice(&self.timestamp.to_le_bytes());
+        put_work(self.work, buf);
+    }
+
+    fn get(r: &mut FixedReader<'_>) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            id: r.u64()?,
+            timestamp: r.u64()?,
+            work: get_work(r)?,
+        })
+    }
+}
+
+impl ServerWorkPacket {
+    fn put(&self, buf: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        let payload = self.payload.as_deref();
+        let reason = self.failure.as_ref().map_or("", |f| f.reason.as_str());
+        let payload_len = match payload {
+            Some(p) if p.len() >= NO_PAYLOAD as usize => bail!("payload of {} bytes", p.len()),
+            Some(p) => p.len() as u32,
+            None => NO_PAYLOAD,
+        };
+        let reason_len = u32::try_from(reason.len())?;
+
+        buf.extend_from_slice(&self.client_id.to_le_bytes());
+        buf.extend_from_slice(&self.client_send_time.to_le_bytes());
+        buf.extend_from_slice(&self.server_processing_time.to_le_bytes());
+        buf.push(self.status as u8);
+        buf.push(self.failure.as_ref().map_or(NO_FAILURE, |f| f.code as u8));
+        buf.extend_from_slice(&0u16.to_le_bytes());
+        buf.extend_from_slice(&payload_len.to_le_bytes());
+        buf.extend_from_slice(&reason_len.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(payload.unwrap_or_default());
+        buf.extend_from_slice(reason.as_bytes());
+        Ok(())
+    }
+
+    fn get(r: &mut FixedReader<'_>) -> Result<Self, anyhow::Error> {
+        ServerWorkPacketRef::get(r).map(|packet| packet.to_owned())
+    }
+}
+
+/// A [`ServerWorkPacket`] read in place from a [`Codec::Fixed`] frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServerWorkPacketRef<'a> {
+    status: ServerWorkStatus,
+    server_processing_time: u64,
+    client_id: u64,
+    client_send_time: u64,
+    payload: Option<&'a [u8]>,
+    failure: Option<(FailureCode, &'a str)>,
+}
+
+impl<'a> ServerWorkPacketRef<'a> {
+    pub fn status(&self) -> ServerWorkStatus {
+        self.status
+    }
+
+    pub fn client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    pub fn client_send_time(&self) -> u64 {
+        self.client_send_time
+    }
+
+    pub fn server_processing_time(&self) -> u64 {
+        self.server_processing_time
+    }
+
+    pub fn payload(&self) -> Option<&'a [u8]> {
+        self.payload
+    }
+
+    /// What went wrong, and why, for a failed response.
+    pub fn failure(&self) -> Option<(FailureCode, &'a str)> {
+        self.failure
+    }
+
+    pub fn to_owned(&self) -> ServerWorkPacket {
+        ServerWorkPacket {
+            status: self.status,
+            server_processing_time: self.server_processing_time,
+            client_id: self.client_id,
+            client_send_time: self.client_send_time,
+            payload: self.payload.map(<[u8]>::to_vec),
+            failure: self.failure.map(|(code, reason)| Failure {
+                code,
+                reason: reason.to_string(),
+            }),
+        }
+    }
+
+    fn get(r: &mut FixedReader<'a>) -> Result<Self, anyhow::Error> {
+        let client_id = r.u64()?;
+        let client_send_time = r.u64()?;
+        let server_processing_time = r.u64()?;
+        let status = match r.u8()? {
+            0 => ServerWorkStatus::Completed,
+            1 => ServerWorkStatus::Failed,
+            s => bail!("unknown status {}", s),
+        };
+        let code = r.u8()?;
+        r.u16()?;
+        let payload_len = r.u32()?;
+        let reason_len = r.u32()?;
+        r.u32()?;
+        let payload = match payload_len {
+            NO_PAYLOAD => None,
+            len => Some(r.bytes(len as usize)?),
+        };
+        let reason = std::str::from_utf8(r.bytes(reason_len as usize)?)?;
+        let failure = match code {
+            NO_FAILURE if reason.is_empty() => None,
+            NO_FAILURE => bail!("failure reason without a failure"),
+            code => Some((
+                *FailureCode::ALL
+                    .get(code as usize)
+                    .ok_or_else(|| anyhow!("unknown failure code {}", code))?,
+                reason,
+            )),
+        };
+        Ok(Self {
+            status,
+            server_processing_time,
+            client_id,
+            client_send_time,
+            payload,
+            failure,
+        })
+    }
+}
+
+/// The variant and packet count leading a fixed-layout [`ClientMessage`] or
+/// [`ServerMessage`].
+fn put_message_header(variant: u32, count: usize, buf: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+    buf.extend_from_slice(&variant.to_le_bytes());
+    buf.extend_from_slice(&u32::try_from(count)?.to_le_bytes());
+    Ok(())
+}
+
+impl MessageTrait for ClientWorkPacket {
+    fn put_fixed(&self, buf: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        self.put(buf);
+        Ok(())
+    }
+
+    fn get_fixed(buf: &[u8]) -> Result<Self, anyhow::Error> {
+        let mut r = FixedReader(buf);
+        let packet = Self::get(&mut r)?;
+        r.finish()?;
+        Ok(packet)
+    }
+}
+
+impl MessageTrait for ServerWorkPacket {
+    fn put_fixed(&self, buf: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        self.put(buf)
+    }
+
+    fn get_fixed(buf: &[u8]) -> Result<Self, anyhow::Error> {
+        let mut r = FixedReader(buf);
+        let packet = Self::get(&mut r)?;
+        r.finish()?;
+        Ok(packet)
+    }
+}
+
+impl MessageTrait for ClientMessage {
+    fn put_fixed(&self, buf: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Work(packet) => {
+                put_message_header(0, 1, buf)?;
+                packet.put(buf);
+            }
+            Self::Batch(packets) => {
+                put_message_header(1, packets.len(), buf)?;
+                packets.iter().for_each(|p| p.put(buf));
+            }
+            Self::Ping(seq) => {
+                put_message_header(2, 0, buf)?;
+                buf.extend_from_slice(&seq.to_le_bytes());
+            }
+            Self::Goodbye => put_message_header(3, 0, buf)?,
+        }
+        Ok(())
+    }
+
+    fn get_fixed(buf: &[u8]) -> Result<Self, anyhow::Error> {
+        let mut r = FixedReader(buf);
+        let (variant, count) = (r.u32()?, r.u32()?);
+        let msg = match (variant, count) {
+            (0, 1) => Self::Work(ClientWorkPacket::get(&mut r)?),
+            (1, count) => {
+                let count = r.room_for(count, FIXED_REQUEST_LEN)?;
+                Self::Batch(
+                    (0..count)
+                        .map(|_| ClientWorkPacket::get(&mut r))
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+            (2, 0) => Self::Ping(r.u64()?),
+            (3, 0) => Self::Goodbye,
+            _ => bail!("unknown message {} with {} packets", variant, count),
+        };
+        r.finish()?;
+        Ok(msg)
+    }
+}
+
+impl MessageTrait for ServerMessage {
+    fn put_fixed(&self, buf: &mut Vec<u8>) -> Result<(), anyhow::Error> {
+        match self {
+            Self::Work(packet) => {
+                put_message_header(0, 1, buf)?;
+                packet.put(buf)?;
+            }
+            Self::Batch(packets) => {
+                put_message_header(1, packets.len(), buf)?;
+                for packet in packets {
+                    packet.put(buf)?;
+                }
+            }
+            Self::Pong(seq) => {
+                put_message_header(2, 0, buf)?;
+                buf.extend_from_slice(&seq.to_le_bytes());
+            }
+            Self::Goodbye => put_message_header(3, 0, buf)?,
+        }
+        Ok(())
+    }
+
+    fn get_fixed(buf: &[u8]) -> Result<Self, anyhow::Error> {
+        ServerMessageRef::decode_fixed(buf).map(|msg| msg.to_owned())
+    }
 }
 
-impl MessageTrait for ClientWorkPacket {}
-impl MessageTrait for ServerWorkPacket {}
+/// A [`ServerMessage`] read in place from a [`Codec::Fixed`] frame.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerMessageRef<'a> {
+    Work(ServerWorkPacketRef<'a>),
+    Batch(Vec<ServerWorkPacketRef<'a>>),
+    Pong(u64),
+    Goodbye,
+}
+
+impl<'a> ServerMessageRef<'a> {
+    pub fn decode_fixed(buf: &'a [u8]) -> Result<Self, anyhow::Error> {
+        let mut r = FixedReader(buf);
+        let (variant, count) = (r.u32()?, r.u32()?);
+        let msg = match (variant, count) {
+            (0, 1) => Self::Work(ServerWorkPacketRef::get(&mut r)?),
+            (1, count) => {
+                let count = r.room_for(count, FIXED_REPLY_HEADER_LEN)?;
+                Self::Batch(
+                    (0..count)
+                        .map(|_| ServerWorkPacketRef::get(&mut r))
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+            (2, 0) => Self::Pong(r.u64()?),
+            (3, 0) => Self::Goodbye,
+            _ => bail!("unknown message {} with {} packets", variant, count),
+        };
+        r.finish()?;
+        Ok(msg)
+    }
+
+    pub fn to_owned(&self) -> ServerMessage {
+        match self {
+            Self::Work(packet) => ServerMessage::Work(packet.to_owned()),
+            Self::Batch(packets) => ServerMessage::Batch(packets.iter().map(|p| p.to_owned()).collect()),
+            Self::Pong(seq) => ServerMessage::Pong(*seq),
+            Self::Goodbye => ServerMessage::Goodbye,
+        }
+    }
+}
 
 #[cfg(test)]
 mod t {
     use crate::serialize::MessageTrait;
 
-    use super::ClientWorkPacket;
+    use super::{
+        ClientMessage, ClientWorkPacket, FailureCode, ServerMessage, ServerWorkPacket,
+        ServerMessageRef, ServerWorkStatus, FIXED_REQUEST_LEN, UNKNOWN_REQUEST_ID,
+    };
+    use crate::{app::Work, config::ServerConfig, handshake::Codec};
+    use std::time::{Duration, Instant};
 
     #[test]
     fn serialize_bounce() {
@@ -151,4 +797,191 @@ mod t {
         let r2 = ClientWorkPacket::from_bytes(&v).expect("deserialize the client packet");
         assert_eq!(r2, r);
     }
+
+    #[test]
+    fn batch_replies() {
+        let batch = ClientMessage::Batch(vec![
+            ClientWorkPacket::new(1, crate::app::Work::Immediate),
+            ClientWorkPacket::new(2, crate::app::Work::Immediate),
+        ]);
+        assert_eq!(batch.len(), 2);
+
+        let joined = batch.do_work(&ServerConfig::default(), Instant::now());
+        assert_eq!(joined.len(), 1);
+        assert!(matches!(&joined[0], ServerMessage::Batch(replies) if replies.len() == 2));
+
+        let config = ServerConfig {
+            split_batches: true,
+            ..Default::default()
+        };
+        let split = batch.do_work(&config, Instant::now());
+        let ids: Vec<_> = split
+            .into_iter()
+            .flat_map(ServerMessage::into_packets)
+            .map(|p| p.client_id())
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn ping_gets_pong() {
+        let ping = ClientMessage::Ping(7);
+        assert!(ping.is_empty());
+        assert_eq!(
+            ping.do_work(&ServerConfig::default(), Instant::now()),
+            vec![ServerMessage::Pong(7)]
+        );
+        assert!(ServerMessage::Pong(7).into_packets().is_empty());
+    }
+
+    fn failure_codes(replies: Vec<ServerMessage>) -> Vec<Option<FailureCode>> {
+        replies
+            .into_iter()
+            .flat_map(ServerMessage::into_packets)
+            .map(|p| p.failure().map(|f| f.code))
+            .collect()
+    }
+
+    #[test]
+    fn failed_replies() {
+        let config = ServerConfig {
+            max_batch_len: 1,
+            request_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let packet = ClientWorkPacket::new(3, crate::app::Work::Immediate);
+
+        let batch = ClientMessage::Batch(vec![packet, packet]);
+        assert_eq!(
+            failure_codes(batch.do_work(&config, Instant::now())),
+            vec![Some(FailureCode::Rejected); 2]
+        );
+
+        let single = ClientMessage::Work(packet);
+        assert_eq!(
+            failure_codes(single.do_work(&config, Instant::now())),
+            vec![None]
+        );
+        let long_ago = Instant::now() - Duration::from_secs(1);
+        let late = single
+            .do_work(&config, long_ago)
+            .remove(0)
+            .into_packets()
+            .remove(0);
+        assert_eq!(late.status(), ServerWorkStatus::Failed);
+        assert_eq!(late.failure().unwrap().code, FailureCode::TimedOut);
+        assert!(late.calculate_latency(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn undecodable_keeps_id_when_possible() {
+        let mut bytes = Vec::new();
+        ClientMessage::Work(ClientWorkPacket::new(9, crate::app::Work::Immediate))
+            .to_vec(&mut bytes)
+            .unwrap();
+        bytes.truncate(14);
+        let err = ClientMessage::from_bytes(&bytes).unwrap_err();
+
+        let reply = ClientMessage::undecodable(Codec::Bincode, &bytes, &err)
+            .into_packets()
+            .remove(0);
+        assert_eq!(reply.client_id(), 9);
+        assert_eq!(reply.failure().unwrap().code, FailureCode::Undecodable);
+
+        let reply = ClientMessage::undecodable(Codec::Bincode, &[1, 2], &err)
+            .into_packets()
+            .remove(0);
+        assert_eq!(reply.client_id(), UNKNOWN_REQUEST_ID);
+    }
+
+    const CODECS: [Codec; 3] = [Codec::Bincode, Codec::Json, Codec::Fixed];
+
+    #[test]
+    fn codecs_round_trip() {
+        let packet = ClientWorkPacket::new(5, Work::Poisson(std::num::NonZeroU64::new(9).unwrap()));
+        let requests = [
+            ClientMessage::Work(packet),
+            ClientMessage::Batch(vec![packet, ClientWorkPacket::new(6, Work::BusyWorkConst(3))]),
+            ClientMessage::Ping(11),
+            ClientMessage::Goodbye,
+        ];
+        let replies = [
+            ServerMessage::Work(ClientWorkPacket::new(1, Work::Payload).do_work()),
+            ServerMessage::Batch(vec![
+                packet.do_work(),
+                ServerWorkPacket::failed(2, 3, FailureCode::Panicked, "boom"),
+            ]),
+            ServerMessage::Pong(11),
+            ServerMessage::Goodbye,
+        ];
+        for codec in CODECS {
+            for msg in &requests {
+                let mut buf = Vec::new();
+                msg.encode(codec, &mut buf).unwrap();
+                assert_eq!(&ClientMessage::decode(codec, &buf).unwrap(), msg, "{:?}", codec);
+            }
+            for msg in &replies {
+                let mut buf = Vec::new();
+                msg.encode(codec, &mut buf).unwrap();
+                assert_eq!(&ServerMessage::decode(codec, &buf).unwrap(), msg, "{:?}", codec);
+            }
+        }
+
+        let mut buf = Vec::new();
+        requests[0].encode(Codec::Fixed, &mut buf).unwrap();
+        assert_eq!(buf.len(), 8 + FIXED_REQUEST_LEN);
+        buf.push(0);
+        assert!(ClientMessage::decode(Codec::Fixed, &buf).is_err());
+        // A batch claiming more packets than the frame holds is refused before allocating.
+        buf.truncate(4);
+        buf.extend_from_slice(&u32::MAX.to_le_bytes());
+        buf[..4].copy_from_slice(&1u32.to_le_bytes());
+        assert!(ClientMessage::decode(Codec::Fixed, &buf).is_err());
+    }
+
+    #[test]
+    fn fixed_replies_borrow_from_the_frame() {
+        let reply = ServerMessage::Batch(vec![
+            ClientWorkPacket::new(1, Work::Payload).do_work(),
+            ServerWorkPacket::failed(2, 3, FailureCode::Panicked, "boom"),
+        ]);
+        let mut buf = Vec::new();
+        reply.encode(Codec::Fixed, &mut buf).unwrap();
+
+        let ServerMessageRef::Batch(packets) = ServerMessageRef::decode_fixed(&buf).unwrap() else {
+            panic!("expected a batch");
+        };
+        let frame = buf.as_ptr_range();
+        let payload = packets[0].payload().unwrap();
+        assert!(!payload.is_empty() && frame.contains(&payload.as_ptr()));
+        let (code, reason) = packets[1].failure().unwrap();
+        assert_eq!((code, reason), (FailureCode::Panicked, "boom"));
+        assert!(frame.contains(&reason.as_ptr()));
+        assert_eq!(ServerMessageRef::Batch(packets).to_owned(), reply);
+    }
+
+    #[test]
+    fn undecodable_keeps_id_with_every_codec() {
+        for codec in CODECS {
+            let mut bytes = Vec::new();
+            ClientMessage::Work(ClientWorkPacket::new(9, Work::Immediate))
+                .encode(codec, &mut bytes)
+                .unwrap();
+            // Break the work, which comes after the id.
+            match codec {
+                Codec::Bincode => bytes.truncate(14),
+                Codec::Json => {
+                    let json = String::from_utf8(bytes).unwrap();
+                    bytes = json.replace("Immediate", "Eventually").into_bytes();
+                }
+                Codec::Fixed => bytes[24] = 99,
+            }
+            let err = ClientMessage::decode(codec, &bytes).unwrap_err();
+            let reply = ClientMessage::undecodable(codec, &bytes, &err)
+                .into_packets()
+                .remove(0);
+            assert_eq!(reply.client_id(), 9, "{:?}", codec);
+        }
+    }
 }
+
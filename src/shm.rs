@@ -0,0 +1,552 @@
+//! A same-host transport with no kernel networking on the request path: bytes go through
+//! two ring buffers (one per direction) in a shared `memfd` mapping, and a reader that has
+//! to wait sleeps on an `eventfd` the writer signals.
+//!
+//! A client sets up a connection by creating the memfd and eventfds and handing them to
+//! the server over a Unix domain socket, which then stays open only so each side notices
+//! if the other process dies.
+
+use libc::c_void;
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use crate::transport::Transport;
+use std::{
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    path::Path,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// Bytes of data in each direction's ring. A power of two.
+pub const RING_CAPACITY: usize = 1 << 18;
+/// Room for the [`RingHeader`] ahead of each ring's data.
+const HEADER_LEN: usize = 256;
+const RING_LEN: usize = HEADER_LEN + RING_CAPACITY;
+/// Times to look at an empty (or full) ring before going to sleep. Waking through the
+/// kernel costs more than the rest of the request path.
+const SPIN_ITERS: usize = 2000;
+
+/// Sent with the descriptors, so a server can tell a shared-memory client from a stray
+/// connection.
+const SETUP_MAGIC: &[u8; 8] = b"WOONSHM1";
+
+/// Shared between the two processes at the start of each ring. `head` and `tail` count
+/// bytes ever written and read, so `head - tail` is what the ring holds.
+#[repr(C)]
+struct RingHeader {
+    head: AtomicU64,
+    _head_line: [u8; 56],
+    tail: AtomicU64,
+    _tail_line: [u8; 56],
+    reader_waiting: AtomicU32,
+    writer_waiting: AtomicU32,
+    /// Set by the writing side once it has gone away.
+    writer_closed: AtomicU32,
+    /// Set by the reading side once it has gone away.
+    reader_closed: AtomicU32,
+}
+
+const _: () = assert!(std::mem::size_of::<RingHeader>() <= HEADER_LEN);
+
+/// One direction of the connection as seen from this process.
+struct Ring {
+    header: *const RingHeader,
+    data: *mut u8,
+    /// Signalled when the ring gains data.
+    data_ready: OwnedFd,
+    /// Signalled when the ring gains space.
+    space_ready: OwnedFd,
+}
+
+impl Ring {
+    fn header(&self) -> &RingHeader {
+        // SAFETY: points into the mapping, which outlives every `Ring`.
+        unsafe { &*self.header }
+    }
+
+    /// Bytes the ring holds, refusing counters a misbehaving peer could have left.
+    fn used(&self, head: u64, tail: u64) -> io::Result<usize> {
+        match head.checked_sub(tail) {
+            Some(used) if used <= RING_CAPACITY as u64 => Ok(used as usize),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "shared memory ring is corrupt",
+            )),
+        }
+    }
+
+    /// Copy `buf` into the ring starting at stream offset `pos`.
+    ///
+    /// # Safety
+    ///
+    /// The writer must own those ring bytes: they have already been read.
+    unsafe fn copy_in(&self, pos: u64, buf: &[u8]) {
+        let (start, first) = self.split(pos, buf.len());
+        ptr::copy_nonoverlapping(buf.as_ptr(), self.data.add(start), first);
+        ptr::copy_nonoverlapping(buf[first..].as_ptr(), self.data, buf.len() - first);
+    }
+
+    /// Fill `buf` from the ring starting at stream offset `pos`.
+    ///
+    /// # Safety
+    ///
+    /// The reader must own those ring bytes: they have been written and not yet read.
+    unsafe fn copy_out(&self, pos: u64, buf: &mut [u8]) {
+        let (start, first) = self.split(pos, buf.len());
+        ptr::copy_nonoverlapping(self.data.add(start), buf.as_mut_ptr(), first);
+        ptr::copy_nonoverlapping(self.data, buf[first..].as_mut_ptr(), buf.len() - first);
+    }
+
+    /// Where `len` bytes at stream offset `pos` start in the ring, and how many of them
+    /// fit before it wraps.
+    fn split(&self, pos: u64, len: usize) -> (usize, usize) {
+        let start = pos as usize & (RING_CAPACITY - 1);
+        (start, len.min(RING_CAPACITY - start))
+    }
+}
+
+/// The mapping and descriptors of one connection, shared by every handle to it.
+struct Shared {
+    map: *mut c_void,
+    rx: Ring,
+    tx: Ring,
+    /// Becomes readable when the peer process closes its end.
+    control: UnixStream,
+    read_lock: Mutex<()>,
+    write_lock: Mutex<()>,
+    read_timeout: Mutex<Option<Duration>>,
+    read_shut: AtomicBool,
+    peer_gone: AtomicBool,
+}
+
+// SAFETY: the raw pointers refer to the shared mapping, which lives as long as `Shared`;
+// access to ring data is serialized by `read_lock`/`write_lock` and the ring counters.
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.tx.header().writer_closed.store(1, Ordering::SeqCst);
+        self.rx.header().reader_closed.store(1, Ordering::SeqCst);
+        signal(&self.tx.data_ready);
+        signal(&self.rx.space_ready);
+        // SAFETY: nothing refers to the mapping any more.
+        unsafe { libc::munmap(self.map, 2 * RING_LEN) };
+    }
+}
+
+/// One handle to a shared-memory connection. Clones share the connection, like
+/// duplicated socket descriptors.
+#[derive(Clone)]
+pub struct ShmStream(std::sync::Arc<Shared>);
+
+impl std::fmt::Debug for ShmStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShmStream")
+            .field("control", &self.0.control)
+            .finish()
+    }
+}
+
+/// Connect to a server listening for shared-memory clients at `path`.
+pub fn connect(path: &Path) -> io::Result<ShmStream> {
+    connect_over(UnixStream::connect(path)?)
+}
+
+/// Create a connection and hand it to the server at the other end of `control`.
+pub fn connect_over(control: UnixStream) -> io::Result<ShmStream> {
+    // SAFETY: plain syscalls; every descriptor is wrapped in an `OwnedFd` right away.
+    let memfd = unsafe { owned(libc::memfd_create(c"woonsocket-shm".as_ptr(), libc::MFD_CLOEXEC))? };
+    if unsafe { libc::ftruncate(memfd.as_raw_fd(), (2 * RING_LEN) as libc::off_t) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let events = [new_eventfd()?, new_eventfd()?, new_eventfd()?, new_eventfd()?];
+
+    let fds = [
+        memfd.as_raw_fd(),
+        events[0].as_raw_fd(),
+        events[1].as_raw_fd(),
+        events[2].as_raw_fd(),
+        events[3].as_raw_fd(),
+    ];
+    sendmsg::<()>(
+        control.as_raw_fd(),
+        &[IoSlice::new(SETUP_MAGIC)],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+
+    // The client writes the first ring and reads the second.
+    let [tx_data, tx_space, rx_data, rx_space] = events;
+    ShmStream::map(memfd, control, (tx_data, tx_space), (rx_data, rx_space), true)
+}
+
+/// Take over the connection a client sent on `control`.
+pub fn accept(control: UnixStream) -> io::Result<ShmStream> {
+    let mut magic = [0u8; 8];
+    let mut cmsg = nix::cmsg_space!([RawFd; 5]);
+    let mut fds = Vec::new();
+    let len = {
+        let mut iov = [IoSliceMut::new(&mut magic)];
+        let msg = recvmsg::<()>(
+            control.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+        for c in msg.cmsgs()? {
+            if let ControlMessageOwned::ScmRights(received) = c {
+                // SAFETY: the kernel just installed these descriptors for us.
+                fds.extend(received.into_iter().map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }));
+            }
+        }
+        msg.bytes
+    };
+    let Ok([memfd, c2s_data, c2s_space, s2c_data, s2c_space]) = <[OwnedFd; 5]>::try_from(fds) else {
+        return Err(not_shm_client());
+    };
+    if len != magic.len() || &magic != SETUP_MAGIC {
+        return Err(not_shm_client());
+    }
+
+    // SAFETY: `stat` is plain old data, filled in by the kernel.
+    let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+    if unsafe { libc::fstat(memfd.as_raw_fd(), &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if stat.st_size as usize != 2 * RING_LEN {
+        return Err(not_shm_client());
+    }
+    ShmStream::map(memfd, control, (s2c_data, s2c_space), (c2s_data, c2s_space), false)
+}
+
+fn not_shm_client() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "peer did not send a shared memory connection",
+    )
+}
+
+impl ShmStream {
+    /// Map `memfd`. The client writes the first ring and the server the second.
+    fn map(
+        memfd: OwnedFd,
+        control: UnixStream,
+        (tx_data, tx_space): (OwnedFd, OwnedFd),
+        (rx_data, rx_space): (OwnedFd, OwnedFd),
+        client: bool,
+    ) -> io::Result<Self> {
+        // SAFETY: a fresh shared mapping of the whole memfd, unmapped when `Shared` drops.
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                2 * RING_LEN,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                memfd.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let ring = |index: usize, data_ready, space_ready| {
+            // SAFETY: both rings lie within the mapping.
+            let base = unsafe { map.cast::<u8>().add(index * RING_LEN) };
+            Ring {
+                header: base.cast(),
+                data: unsafe { base.add(HEADER_LEN) },
+                data_ready,
+                space_ready,
+            }
+        };
+        let (tx_index, rx_index) = if client { (0, 1) } else { (1, 0) };
+        Ok(Self(std::sync::Arc::new(Shared {
+            map,
+            tx: ring(tx_index, tx_data, tx_space),
+            rx: ring(rx_index, rx_data, rx_space),
+            control,
+            read_lock: Mutex::new(()),
+            write_lock: Mutex::new(()),
+            read_timeout: Mutex::new(None),
+            read_shut: AtomicBool::new(false),
+            peer_gone: AtomicBool::new(false),
+        })))
+    }
+
+    /// Applies to every handle, as a socket's timeout does.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.0.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    /// Only stopping reads does anything: reads return what has already arrived, then end
+    /// of file, and a blocked read wakes up. Writing stops when the last handle is dropped.
+    pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
+        if how != std::net::Shutdown::Write {
+            self.0.read_shut.store(true, Ordering::SeqCst);
+            signal(&self.0.rx.data_ready);
+        }
+        Ok(())
+    }
+
+    /// The control socket; bytes written there bypass the rings.
+    pub fn control(&self) -> &UnixStream {
+        &self.0.control
+    }
+}
+
+impl Transport for ShmStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(self.clone())
+    }
+}
+
+impl Read for ShmStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let shared = &*self.0;
+        let _reading = shared.read_lock.lock().unwrap();
+        let ring = &shared.rx;
+        let header = ring.header();
+        let timeout = *shared.read_timeout.lock().unwrap();
+        let mut spins = 0;
+        loop {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            let tail = header.tail.load(Ordering::Relaxed);
+            let used = ring.used(header.head.load(Ordering::SeqCst), tail)?;
+            if used > 0 {
+                let n = used.min(buf.len());
+                // SAFETY: the `used` bytes after `tail` are written and ours to read.
+                unsafe { ring.copy_out(tail, &mut buf[..n]) };
+                header.tail.store(tail + n as u64, Ordering::SeqCst);
+                if header.writer_waiting.load(Ordering::SeqCst) != 0 {
+                    signal(&ring.space_ready);
+                }
+                return Ok(n);
+            }
+            if header.writer_closed.load(Ordering::SeqCst) != 0
+                || shared.peer_gone.load(Ordering::SeqCst)
+                || shared.read_shut.load(Ordering::SeqCst)
+            {
+                // Bytes may have landed after the ring was found empty; read those first.
+                if header.head.load(Ordering::SeqCst) == tail {
+                    return Ok(0);
+                }
+                continue;
+            }
+            if spins < SPIN_ITERS {
+                spins += 1;
+                std::hint::spin_loop();
+                continue;
+            }
+
+            header.reader_waiting.store(1, Ordering::SeqCst);
+            let woke = if header.head.load(Ordering::SeqCst) == tail
+                && header.writer_closed.load(Ordering::SeqCst) == 0
+                && !shared.read_shut.load(Ordering::SeqCst)
+            {
+                shared.sleep(&ring.data_ready, timeout)
+            } else {
+                Ok(true)
+            };
+            header.reader_waiting.store(0, Ordering::SeqCst);
+            if !woke? {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+        }
+    }
+}
+
+impl Write for ShmStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let shared = &*self.0;
+        let _writing = shared.write_lock.lock().unwrap();
+        let ring = &shared.tx;
+        let header = ring.header();
+        let mut spins = 0;
+        loop {
+            if header.reader_closed.load(Ordering::SeqCst) != 0
+                || shared.peer_gone.load(Ordering::SeqCst)
+            {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            let head = header.head.load(Ordering::Relaxed);
+            let free = RING_CAPACITY - ring.used(head, header.tail.load(Ordering::SeqCst))?;
+            if free > 0 {
+                let n = free.min(buf.len());
+                // SAFETY: the `free` bytes after `head` have been read and are ours to fill.
+                unsafe { ring.copy_in(head, &buf[..n]) };
+                header.head.store(head + n as u64, Ordering::SeqCst);
+                if header.reader_waiting.load(Ordering::SeqCst) != 0 {
+                    signal(&ring.data_ready);
+                }
+                return Ok(n);
+            }
+            if spins < SPIN_ITERS {
+                spins += 1;
+                std::hint::spin_loop();
+                continue;
+            }
+
+            header.writer_waiting.store(1, Ordering::SeqCst);
+            let result = if header.tail.load(Ordering::SeqCst) + RING_CAPACITY as u64 == head {
+                shared.sleep(&ring.space_ready, None)
+            } else {
+                Ok(true)
+            };
+            header.writer_waiting.store(0, Ordering::SeqCst);
+            result?;
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Shared {
+    /// Wait for `event`, for the peer to go away, or for `timeout`. Returns false on
+    /// timing out.
+    fn sleep(&self, event: &OwnedFd, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut fds = [
+            libc::pollfd {
+                fd: event.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.control.as_raw_fd(),
+                events: libc::POLLIN | libc::POLLRDHUP,
+                revents: 0,
+            },
+        ];
+        let timeout_ms = timeout.map_or(-1, |t| t.as_millis().clamp(1, i32::MAX as u128) as i32);
+        // SAFETY: `fds` is a valid array of two pollfds.
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), 2, timeout_ms) };
+        if ready < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::Interrupted => Ok(true),
+                _ => Err(err),
+            };
+        }
+        if fds[0].revents != 0 {
+            let mut count = 0u64;
+            // SAFETY: an eventfd read is exactly 8 bytes. It's non-blocking, so a counter
+            // someone else already reset only gives EAGAIN.
+            unsafe { libc::read(event.as_raw_fd(), (&mut count as *mut u64).cast(), 8) };
+        }
+        // Nothing is ever sent on the control socket after setup, so it only becomes
+        // readable when the peer closes it.
+        if fds[1].revents != 0 {
+            self.peer_gone.store(true, Ordering::SeqCst);
+        }
+        Ok(ready > 0)
+    }
+}
+
+fn new_eventfd() -> io::Result<OwnedFd> {
+    // SAFETY: plain syscall, result checked by `owned`.
+    unsafe { owned(libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK)) }
+}
+
+/// # Safety
+///
+/// `fd` must be a descriptor nothing else owns, or negative for an error.
+unsafe fn owned(fd: RawFd) -> io::Result<OwnedFd> {
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
+
+fn signal(event: &OwnedFd) {
+    let one = 1u64;
+    // SAFETY: an eventfd write is exactly 8 bytes. Failing means the counter is already
+    // huge, so the waiter will wake anyway.
+    unsafe { libc::write(event.as_raw_fd(), (&one as *const u64).cast(), 8) };
+}
+
+#[cfg(test)]
+mod t {
+    use super::{accept, connect_over, ShmStream, RING_CAPACITY};
+    use crate::{
+        app::Work,
+        chunked_tcp_stream::ChunkedTcpStream,
+        protocol::FramedConn,
+        serialize::ClientWorkPacket,
+        transport::Stream,
+    };
+    use std::{
+        io::{self, Read, Write},
+        net::Shutdown,
+        os::unix::net::UnixStream,
+        thread,
+        time::Duration,
+    };
+
+    fn pair() -> (ShmStream, ShmStream) {
+        let (a, b) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || accept(b).unwrap());
+        (connect_over(a).unwrap(), server.join().unwrap())
+    }
+
+    #[test]
+    fn shm_framed_bounce_past_ring_end() {
+        let (client, server) = pair();
+        let echo = thread::spawn(move || {
+            let mut conn = FramedConn::<ClientWorkPacket>::new(ChunkedTcpStream::new(Stream::Shm(server)));
+            while let Ok(msg) = conn.recv_msg() {
+                conn.send_msg(&msg).unwrap();
+            }
+        });
+
+        let (mut tx, mut rx) = FramedConn::<ClientWorkPacket>::new(ChunkedTcpStream::new(Stream::Shm(client)))
+            .split()
+            .unwrap();
+        // Enough traffic to wrap around the rings several times.
+        let n = 4 * RING_CAPACITY as u64 / 32;
+        let receiver = thread::spawn(move || (0..n).map(|_| rx.recv_msg().unwrap().id()).collect::<Vec<_>>());
+        for id in 0..n {
+            tx.send_msg(&ClientWorkPacket::new(id, Work::Immediate)).unwrap();
+        }
+        assert_eq!(receiver.join().unwrap(), (0..n).collect::<Vec<_>>());
+        drop(tx);
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn shm_timeout_shutdown_and_close() {
+        let (client, server) = pair();
+        let mut buf = [0u8; 8];
+
+        let (mut client, mut server) = (client, server);
+        server.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        assert_eq!(server.read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        server.set_read_timeout(None).unwrap();
+        let mut reader = server.clone();
+        let blocked = thread::spawn(move || reader.read(&mut [0u8; 8]).unwrap());
+        thread::sleep(Duration::from_millis(20));
+        server.shutdown(Shutdown::Read).unwrap();
+        assert_eq!(blocked.join().unwrap(), 0);
+
+        drop(server);
+        assert_eq!(client.write(b"x").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+    }
+}
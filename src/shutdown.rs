@@ -0,0 +1,161 @@
+//! Ending a server run cleanly: every open connection stops reading, answers what it has
+//! already received, and tells its client goodbye.
+
+use crate::transport::Stream;
+#[cfg(test)]
+use crate::{config::ServerConfig, stats::ServerStats, transport::Accepted};
+use std::{
+    collections::HashMap,
+    io,
+    net::Shutdown as SocketShutdown,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Shared by the accept loop of one server and by whoever decides the run is over.
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    next_id: AtomicU64,
+    conns: Mutex<HashMap<u64, Stream>>,
+}
+
+/// Keeps a connection known to [`Shutdown`] until dropped.
+pub struct Registration<'a> {
+    shutdown: &'a Shutdown,
+    id: u64,
+}
+
+impl Shutdown {
+    /// Track `stream` so that [`Self::begin`] can wake up a thread blocked reading it. If
+    /// shutdown has already begun, reading is stopped right away.
+    pub fn register(&self, stream: &Stream) -> io::Result<Registration<'_>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let clone = stream.try_clone()?;
+        let mut conns = self.conns.lock().unwrap();
+        if self.is_requested() {
+            clone.shutdown(SocketShutdown::Read)?;
+        }
+        conns.insert(id, clone);
+        Ok(Registration { shutdown: self, id })
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Stop reading on every connection. Blocked receives return end-of-file, which the
+    /// connection threads take as their cue to say goodbye.
+    pub fn begin(&self) {
+        let conns = self.conns.lock().unwrap();
+        self.requested.store(true, Ordering::SeqCst);
+        for stream in conns.values() {
+            // The connection may already be gone, which is fine.
+            let _ = stream.shutdown(SocketShutdown::Read);
+        }
+    }
+
+    /// Wait up to `timeout` for every registered connection to finish. Returns how many
+    /// are still open.
+    pub fn wait_closed(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let open = self.conns.lock().unwrap().len();
+            if open == 0 || Instant::now() >= deadline {
+                return open;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.shutdown.conns.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Queue requests on `client`, shut down, and check that every one is answered before the
+/// goodbye and that the connection then ends.
+#[cfg(test)]
+pub(crate) fn assert_drains(client: Stream, shutdown: &Shutdown) {
+    use crate::{
+        app::Work,
+        chunked_tcp_stream::ChunkedTcpStream,
+        handshake::{self, ConnParams},
+        protocol::{FramedReceiver, FramedSender},
+        serialize::{ClientMessage, ClientWorkPacket, ServerMessage},
+    };
+
+    let mut stream = ChunkedTcpStream::new(client);
+    let params = handshake::connect(&mut stream, ConnParams::default()).unwrap();
+    let mut tx = FramedSender::<ClientMessage>::new(stream.try_clone().unwrap()).with_params(params);
+    let mut rx = FramedReceiver::<ServerMessage>::new(stream).with_params(params);
+    for id in 0..8 {
+        tx.send_msg(&ClientMessage::Work(ClientWorkPacket::new(id, Work::Const(5)))).unwrap();
+    }
+    shutdown.begin();
+
+    // Workers may answer out of order.
+    let mut answered: Vec<_> = (0..8)
+        .map(|_| match rx.recv_msg().unwrap() {
+            ServerMessage::Work(reply) => reply.client_id(),
+            other => panic!("expected a reply before the goodbye, got {:?}", other),
+        })
+        .collect();
+    answered.sort_unstable();
+    assert_eq!(answered, (0..8).collect::<Vec<_>>());
+    assert_eq!(rx.recv_msg().unwrap(), ServerMessage::Goodbye);
+    assert!(rx.recv_msg().unwrap_err().is_clean_eof());
+}
+
+/// [`assert_drains`] against `handle_conn` serving the other end of a Unix socket pair.
+#[cfg(test)]
+pub(crate) fn drains_on_shutdown(
+    handle_conn: fn(Accepted, &ServerConfig, &ServerStats, &Shutdown),
+    config: ServerConfig,
+) {
+    drains_over(handle_conn, config, false);
+}
+
+/// [`drains_on_shutdown`] with shared memory set up over the socket pair.
+#[cfg(test)]
+pub(crate) fn drains_shm_on_shutdown(
+    handle_conn: fn(Accepted, &ServerConfig, &ServerStats, &Shutdown),
+    config: ServerConfig,
+) {
+    drains_over(handle_conn, config, true);
+}
+
+#[cfg(test)]
+fn drains_over(
+    handle_conn: fn(Accepted, &ServerConfig, &ServerStats, &Shutdown),
+    config: ServerConfig,
+    shm: bool,
+) {
+    use std::os::unix::net::UnixStream;
+
+    let (client, server) = UnixStream::pair().unwrap();
+    let stats = ServerStats::default();
+    let shutdown = Shutdown::default();
+    thread::scope(|s| {
+        let accepted = if shm {
+            Accepted::Shm(server)
+        } else {
+            Accepted::Ready(Stream::Unix(server))
+        };
+        let conn = s.spawn(|| handle_conn(accepted, &config, &stats, &shutdown));
+        let client = if shm {
+            Stream::Shm(crate::shm::connect_over(client).unwrap())
+        } else {
+            Stream::Unix(client)
+        };
+        assert_drains(client, &shutdown);
+        conn.join().unwrap();
+    });
+    assert_eq!(shutdown.wait_closed(Duration::ZERO), 0);
+}
@@ -0,0 +1,261 @@
+//! Kernel socket options, set the same way by every server kind and client so runs can be
+//! compared. Each option left unset keeps the kernel's default.
+
+use crate::transport::{Listener, Stream};
+use std::{
+    fmt, io, mem,
+    net::UdpSocket,
+    os::fd::{AsRawFd, RawFd},
+    sync::Mutex,
+};
+
+/// Listen backlog when none is configured; what the standard library uses.
+pub const DEFAULT_BACKLOG: u32 = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq, clap::Args)]
+pub struct SocketOptions {
+    /// Set `TCP_NODELAY`, so small writes go out without waiting.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set, help = "Set TCP_NODELAY")]
+    pub nodelay: bool,
+    /// Set `TCP_QUICKACK` when a connection opens. The kernel may clear it again later.
+    #[arg(long, help = "Set TCP_QUICKACK on each new connection")]
+    pub quickack: bool,
+    /// `SO_RCVBUF`, in bytes. The kernel doubles it for bookkeeping.
+    #[arg(long, value_name = "BYTES", help = "Set SO_RCVBUF")]
+    pub rcvbuf: Option<u32>,
+    /// `SO_SNDBUF`, in bytes. The kernel doubles it for bookkeeping.
+    #[arg(long, value_name = "BYTES", help = "Set SO_SNDBUF")]
+    pub sndbuf: Option<u32>,
+    /// `SO_BUSY_POLL`, in microseconds. Raising it may need `CAP_NET_ADMIN`.
+    #[arg(long, value_name = "US", help = "Set SO_BUSY_POLL, in microseconds")]
+    pub busy_poll_us: Option<u32>,
+    /// `TCP_USER_TIMEOUT`, in milliseconds: how long sent data may stay unacknowledged
+    /// before the connection is dropped.
+    #[arg(long, value_name = "MS", help = "Set TCP_USER_TIMEOUT, in milliseconds")]
+    pub user_timeout_ms: Option<u32>,
+    /// Listen backlog, for servers. Not a client flag.
+    #[arg(skip = DEFAULT_BACKLOG)]
+    pub backlog: u32,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            quickack: false,
+            rcvbuf: None,
+            sndbuf: None,
+            busy_poll_us: None,
+            user_timeout_ms: None,
+            backlog: DEFAULT_BACKLOG,
+        }
+    }
+}
+
+/// Options as the kernel reports them after setting, for the run output. `None` for options
+/// that don't apply to the socket.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Effective {
+    pub nodelay: Option<bool>,
+    pub quickack: Option<bool>,
+    pub rcvbuf: Option<u32>,
+    pub sndbuf: Option<u32>,
+    pub busy_poll_us: Option<u32>,
+    pub user_timeout_ms: Option<u32>,
+}
+
+impl SocketOptions {
+    /// Set the options that apply to `stream`'s kind of socket and read them back. Shared
+    /// memory has no socket to set anything on.
+    pub fn apply(&self, stream: &Stream) -> io::Result<Effective> {
+        match stream {
+            Stream::Tcp(s) => self.apply_fd(s.as_raw_fd(), true),
+            Stream::Unix(s) => self.apply_fd(s.as_raw_fd(), false),
+            Stream::Shm(_) => Ok(Effective::default()),
+            #[cfg(feature = "tls")]
+            Stream::Tls(s) => self.apply_fd(s.socket().as_raw_fd(), true),
+        }
+    }
+
+    /// Set the backlog, and options accepted connections inherit, on `listener`.
+    pub fn apply_listener(&self, listener: &Listener) -> io::Result<Effective> {
+        let fd = listener.as_raw_fd();
+        // SAFETY: plain syscall on a descriptor we own. Listening again only updates the
+        // backlog.
+        check(unsafe { libc::listen(fd, self.backlog.min(i32::MAX as u32) as i32) })?;
+        match listener {
+            Listener::Tcp(_) => self.apply_fd(fd, true),
+            Listener::Unix(_) | Listener::Shm(_) => self.apply_fd(fd, false),
+        }
+    }
+
+    /// Set the options that apply to datagram sockets.
+    pub fn apply_udp(&self, socket: &UdpSocket) -> io::Result<Effective> {
+        self.apply_fd(socket.as_raw_fd(), false)
+    }
+
+    fn apply_fd(&self, fd: RawFd, tcp: bool) -> io::Result<Effective> {
+        let set = |level, name, value: u32, what| {
+            set_int(fd, level, name, value)
+                .map_err(|e| io::Error::new(e.kind(), format!("setting {}: {}", what, e)))
+        };
+        if let Some(bytes) = self.rcvbuf {
+            set(libc::SOL_SOCKET, libc::SO_RCVBUF, bytes, "SO_RCVBUF")?;
+        }
+        if let Some(bytes) = self.sndbuf {
+            set(libc::SOL_SOCKET, libc::SO_SNDBUF, bytes, "SO_SNDBUF")?;
+        }
+        if let Some(us) = self.busy_poll_us {
+            set(libc::SOL_SOCKET, libc::SO_BUSY_POLL, us, "SO_BUSY_POLL")?;
+        }
+        if tcp {
+            let nodelay = self.nodelay as u32;
+            set(libc::IPPROTO_TCP, libc::TCP_NODELAY, nodelay, "TCP_NODELAY")?;
+            if self.quickack {
+                set(libc::IPPROTO_TCP, libc::TCP_QUICKACK, 1, "TCP_QUICKACK")?;
+            }
+            if let Some(ms) = self.user_timeout_ms {
+                set(libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT, ms, "TCP_USER_TIMEOUT")?;
+            }
+        }
+        effective(fd, tcp)
+    }
+}
+
+fn effective(fd: RawFd, tcp: bool) -> io::Result<Effective> {
+    let tcp_opt = |name| tcp.then(|| get_int(fd, libc::IPPROTO_TCP, name)).transpose();
+    Ok(Effective {
+        nodelay: tcp_opt(libc::TCP_NODELAY)?.map(|v| v != 0),
+        quickack: tcp_opt(libc::TCP_QUICKACK)?.map(|v| v != 0),
+        rcvbuf: Some(get_int(fd, libc::SOL_SOCKET, libc::SO_RCVBUF)?),
+        sndbuf: Some(get_int(fd, libc::SOL_SOCKET, libc::SO_SNDBUF)?),
+        busy_poll_us: Some(get_int(fd, libc::SOL_SOCKET, libc::SO_BUSY_POLL)?),
+        user_timeout_ms: tcp_opt(libc::TCP_USER_TIMEOUT)?,
+    })
+}
+
+impl Effective {
+    /// Print these, prefixed with `who`.
+    pub fn log(&self, who: &str) {
+        println!("[{}] socket options: {}", who, self);
+    }
+
+    /// Print these the first time `who` logs any: every connection it makes or accepts
+    /// gets the same options, so one line each is enough for the run output.
+    pub fn log_once(&self, who: &str) {
+        if first_log(who) {
+            self.log(who);
+        }
+    }
+}
+
+fn first_log(who: &str) -> bool {
+    static LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    let mut logged = LOGGED.lock().unwrap();
+    let first = !logged.iter().any(|w| w == who);
+    if first {
+        logged.push(who.to_string());
+    }
+    first
+}
+
+impl fmt::Display for Effective {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = [
+            ("nodelay", self.nodelay.map(u32::from)),
+            ("quickack", self.quickack.map(u32::from)),
+            ("rcvbuf", self.rcvbuf),
+            ("sndbuf", self.sndbuf),
+            ("busy_poll_us", self.busy_poll_us),
+            ("user_timeout_ms", self.user_timeout_ms),
+        ];
+        let mut any = false;
+        for (name, value) in fields {
+            if let Some(value) = value {
+                write!(f, "{}{}={}", if any { " " } else { "" }, name, value)?;
+                any = true;
+            }
+        }
+        if !any {
+            write!(f, "none")?;
+        }
+        Ok(())
+    }
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn set_int(fd: RawFd, level: libc::c_int, name: libc::c_int, value: u32) -> io::Result<()> {
+    let value = value.min(i32::MAX as u32) as libc::c_int;
+    // SAFETY: `value` is a c_int and its size is passed along.
+    check(unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    })
+}
+
+fn get_int(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<u32> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` has room for the c_int the kernel writes, as `len` says.
+    check(unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            (&mut value as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    })?;
+    Ok(value as u32)
+}
+
+#[cfg(test)]
+mod t {
+    use super::{first_log, SocketOptions};
+    use crate::transport::{Endpoint, Listener, Stream};
+
+    #[test]
+    fn options_take_effect() {
+        let options = SocketOptions {
+            rcvbuf: Some(64 * 1024),
+            user_timeout_ms: Some(2500),
+            backlog: 16,
+            ..Default::default()
+        };
+        let listener = Listener::bind(&Endpoint::resolve("127.0.0.1", 0).unwrap()).unwrap();
+        options.apply_listener(&listener).unwrap();
+        let Listener::Tcp(l) = &listener else {
+            unreachable!()
+        };
+        let stream = Stream::connect(&Endpoint::Tcp(l.local_addr().unwrap())).unwrap();
+        let effective = options.apply(&stream).unwrap();
+
+        assert_eq!(effective.nodelay, Some(true));
+        // The kernel doubles buffer sizes.
+        assert_eq!(effective.rcvbuf, Some(128 * 1024));
+        assert_eq!(effective.user_timeout_ms, Some(2500));
+        let shown = effective.to_string();
+        assert!(shown.starts_with("nodelay=1 quickack="), "{}", shown);
+        assert!(shown.contains("user_timeout_ms=2500"), "{}", shown);
+    }
+
+    #[test]
+    fn each_caller_logs_once() {
+        assert!(first_log("each_caller_logs_once listener"));
+        assert!(first_log("each_caller_logs_once"));
+        assert!(!first_log("each_caller_logs_once"));
+        assert!(!first_log("each_caller_logs_once listener"));
+    }
+}
@@ -0,0 +1,209 @@
+//! Counters reported at the end of a run.
+
+use crate::{
+    handshake::HandshakeError,
+    pipeline::PipelineError,
+    protocol::ProtocolError,
+    serialize::{FailureCode, ServerMessage, ServerWorkPacket},
+};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Shared by every connection of one server.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    pub connections: AtomicU64,
+    pub requests: AtomicU64,
+    /// Requests answered with a failed response, including ones that did not decode.
+    pub failed_requests: AtomicU64,
+    pub handshake_failures: AtomicU64,
+    /// Clients that said goodbye, or closed the connection between frames.
+    pub clean_disconnects: AtomicU64,
+    /// Clients that sent something we could not parse.
+    pub protocol_violations: AtomicU64,
+    /// Requests dropped because their CRC32C trailer did not match.
+    pub checksum_failures: AtomicU64,
+    /// Connections closed because the client went quiet for longer than the idle timeout.
+    pub idle_timeouts: AtomicU64,
+    pub io_errors: AtomicU64,
+    /// Receives that found every provided buffer in use (`ENOBUFS`) and were posted again.
+    pub buffer_exhaustions: AtomicU64,
+}
+
+impl ServerStats {
+    /// Count `requests` handled, and the failures among their `replies`.
+    pub fn record_replies(&self, requests: usize, replies: &[ServerMessage]) {
+        self.requests.fetch_add(requests as u64, Ordering::Relaxed);
+        let failed = replies
+            .iter()
+            .map(|reply| match reply {
+                ServerMessage::Work(p) => p.failure().is_some() as u64,
+                ServerMessage::Batch(ps) => {
+                    ps.iter().filter(|p| p.failure().is_some()).count() as u64
+                }
+                _ => 0,
+            })
+            .sum();
+        self.failed_requests.fetch_add(failed, Ordering::Relaxed);
+    }
+
+    pub fn record_handshake_failure(&self, server: &str, err: &HandshakeError) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+        eprintln!("[{}] handshake error: {}", server, err);
+    }
+
+    pub fn record_checksum_failure(&self, server: &str, err: &ProtocolError) {
+        self.checksum_failures.fetch_add(1, Ordering::Relaxed);
+        eprintln!("[{}] dropped request: {}", server, err);
+    }
+
+    /// Count, and log unless it was a clean disconnect, the error that ended a connection.
+    pub fn record_close(&self, server: &str, err: &ProtocolError) {
+        if err.is_clean_eof() {
+            self.clean_disconnects.fetch_add(1, Ordering::Relaxed);
+        } else if err.is_violation() {
+            self.protocol_violations.fetch_add(1, Ordering::Relaxed);
+            eprintln!("[{}] protocol violation: {}", server, err);
+        } else if err.is_timeout() {
+            self.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+            eprintln!("[{}] closing idle connection", server);
+        } else {
+            self.io_errors.fetch_add(1, Ordering::Relaxed);
+            eprintln!("[{}] connection error: {}", server, err);
+        }
+    }
+}
+
+impl std::fmt::Display for ServerStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "connections={} requests={} failed_requests={} handshake_failures={} clean_disconnects={} protocol_violations={} checksum_failures={} idle_timeouts={} io_errors={} buffer_exhaustions={}",
+            self.connections.load(Ordering::Relaxed),
+            self.requests.load(Ordering::Relaxed),
+            self.failed_requests.load(Ordering::Relaxed),
+            self.handshake_failures.load(Ordering::Relaxed),
+            self.clean_disconnects.load(Ordering::Relaxed),
+            self.protocol_violations.load(Ordering::Relaxed),
+            self.checksum_failures.load(Ordering::Relaxed),
+            self.idle_timeouts.load(Ordering::Relaxed),
+            self.io_errors.load(Ordering::Relaxed),
+            self.buffer_exhaustions.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Shared by every connection of one load generator run.
+#[derive(Debug, Default)]
+pub struct ClientStats {
+    /// Responses dropped because their CRC32C trailer did not match.
+    pub checksum_failures: AtomicU64,
+    /// Responses that matched no outstanding request.
+    pub unmatched_responses: AtomicU64,
+    /// Connections abandoned because the server stopped answering.
+    pub dead_servers: AtomicU64,
+    /// Connections that failed or ended without the server's goodbye.
+    pub closed_connections: AtomicU64,
+    /// Requests still waiting for an answer when their connection ended.
+    pub unanswered_requests: AtomicU64,
+    /// Requests with no response within the response timeout (UDP only).
+    pub lost_responses: AtomicU64,
+    /// Responses that arrived after one for a later request did (UDP only).
+    pub reordered_responses: AtomicU64,
+    /// Failed responses, indexed by [`FailureCode`].
+    pub failures: [AtomicU64; FailureCode::ALL.len()],
+    /// Connections set up, and the total and longest time their handshakes (TLS, if on,
+    /// and ours) took. Kept apart from request latencies.
+    pub handshakes: AtomicU64,
+    pub handshake_us_total: AtomicU64,
+    pub handshake_us_max: AtomicU64,
+}
+
+impl ClientStats {
+    pub fn record_checksum_failure(&self, err: &ProtocolError) {
+        self.checksum_failures.fetch_add(1, Ordering::Relaxed);
+        eprintln!("dropped response: {}", err);
+    }
+
+    /// Count a failed response. Returns false, counting nothing, for a successful one.
+    pub fn record_failure(&self, packet: &ServerWorkPacket) -> bool {
+        match packet.failure() {
+            Some(failure) => {
+                self.failures[failure.code as usize].fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn record_handshake(&self, took: Duration) {
+        let us = took.as_micros() as u64;
+        self.handshakes.fetch_add(1, Ordering::Relaxed);
+        self.handshake_us_total.fetch_add(us, Ordering::Relaxed);
+        self.handshake_us_max.fetch_max(us, Ordering::Relaxed);
+    }
+
+    pub fn record_unmatched_response(&self, err: &PipelineError) {
+        self.unmatched_responses.fetch_add(1, Ordering::Relaxed);
+        eprintln!("dropped response: {}", err);
+    }
+
+    /// Note that the server said goodbye (or vanished) with `unanswered` requests pending.
+    pub fn record_unanswered(&self, server: impl std::fmt::Display, unanswered: u64) {
+        if unanswered > 0 {
+            self.unanswered_requests
+                .fetch_add(unanswered, Ordering::Relaxed);
+            eprintln!(
+                "server {} closed with {} requests unanswered",
+                server, unanswered
+            );
+        }
+    }
+
+    pub fn record_close(&self, server: impl std::fmt::Display, err: &PipelineError) {
+        self.closed_connections.fetch_add(1, Ordering::Relaxed);
+        eprintln!("connection to server {} ended: {}", server, err);
+    }
+
+    pub fn record_dead_server(&self, server: impl std::fmt::Display, silent_for: Duration) {
+        self.dead_servers.fetch_add(1, Ordering::Relaxed);
+        eprintln!(
+            "server {} silent for {:?}, giving up on the connection",
+            server, silent_for
+        );
+    }
+}
+
+impl std::fmt::Display for ClientStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "checksum_failures={} unmatched_responses={} dead_servers={} closed_connections={} unanswered_requests={} lost_responses={} reordered_responses={}",
+            self.checksum_failures.load(Ordering::Relaxed),
+            self.unmatched_responses.load(Ordering::Relaxed),
+            self.dead_servers.load(Ordering::Relaxed),
+            self.closed_connections.load(Ordering::Relaxed),
+            self.unanswered_requests.load(Ordering::Relaxed),
+            self.lost_responses.load(Ordering::Relaxed),
+            self.reordered_responses.load(Ordering::Relaxed),
+        )?;
+        for code in FailureCode::ALL {
+            write!(
+                f,
+                " failed_{}={}",
+                code,
+                self.failures[code as usize].load(Ordering::Relaxed)
+            )?;
+        }
+        let handshakes = self.handshakes.load(Ordering::Relaxed);
+        write!(
+            f,
+            " handshakes={} handshake_mean_us={} handshake_max_us={}",
+            handshakes,
+            self.handshake_us_total.load(Ordering::Relaxed) / handshakes.max(1),
+            self.handshake_us_max.load(Ordering::Relaxed),
+        )
+    }
+}
@@ -1,53 +1,280 @@
 use std::{
-    net::{SocketAddrV4, TcpStream, TcpListener},
+    sync::{atomic::Ordering, mpsc, Arc, Mutex},
     thread,
+    time::Instant,
 };
 
 use crate::{
     chunked_tcp_stream::ChunkedTcpStream,
-    protocol::work_request::ClientWorkPacketConn,
-    protocol::work_response::ServerWorkPacketConn,
+    config::ServerConfig,
+    handshake,
+    protocol::{FramedReceiver, FramedSender, ProtocolError},
+    serialize::{ClientMessage, MessageTrait, ServerMessage},
+    shutdown::Shutdown,
+    stats::ServerStats,
+    transport::{Accepted, Endpoint, Listener},
 };
 
 // TODO: Students will have to fill in tcp_server, handle_conn and use the code
 // they implemented in recv_work_msg and do_work
 
-pub fn tcp_server(addr: SocketAddrV4) {
-    let listener = TcpListener::bind(addr).expect("failed to bind TCP listener");
-    println!("server listening on {}", addr);
+pub fn tcp_server(
+    endpoint: Endpoint,
+    config: ServerConfig,
+    stats: Arc<ServerStats>,
+    shutdown: Arc<Shutdown>,
+) {
+    let listener = Listener::bind(&endpoint).expect("failed to bind listener");
+    config
+        .socket
+        .apply_listener(&listener)
+        .expect("failed to set socket options")
+        .log("tcp_server listener");
+    println!("server listening on {} (backlog {})", endpoint, config.socket.backlog);
 
     for stream in listener.incoming() {
         match stream {
-            Ok(stream) => { thread::spawn(move || handle_conn(stream)); }
+            Ok(stream) => {
+                let config = config.clone();
+                let stats = stats.clone();
+                let shutdown = shutdown.clone();
+                thread::spawn(move || handle_conn(stream, &config, &stats, &shutdown));
+            }
             Err(e) => { eprintln!("Incoming Error: {}", e); }
         }
     }
 }
 
-fn handle_conn(stream: TcpStream) {
-    let stream_clone = stream.try_clone().unwrap();
-    let mut client_conn = ClientWorkPacketConn::new(ChunkedTcpStream::new(stream_clone));
-    let mut server_conn = ServerWorkPacketConn::new(ChunkedTcpStream::new(stream));
+fn handle_conn(accepted: Accepted, config: &ServerConfig, stats: &ServerStats, shutdown: &Shutdown) {
+    stats.connections.fetch_add(1, Ordering::Relaxed);
+    let stream = match accepted.establish(config.idle_timeout) {
+        Ok(stream) => stream,
+        Err(e) => {
+            stats.record_handshake_failure("tcp_server", &ProtocolError::Io(e).into());
+            return;
+        }
+    };
+    match config.socket.apply(&stream) {
+        Ok(effective) => effective.log_once("tcp_server"),
+        Err(e) => {
+            stats.record_close("tcp_server", &e.into());
+            return;
+        }
+    }
+    let _registration = match shutdown.register(&stream) {
+        Ok(registration) => registration,
+        Err(e) => {
+            stats.record_close("tcp_server", &e.into());
+            return;
+        }
+    };
+    // Reads that time out end the connection, so a client that vanished without a FIN
+    // doesn't hold this thread forever.
+    if let Err(e) = stream.set_read_timeout(config.idle_timeout) {
+        stats.record_close("tcp_server", &e.into());
+        return;
+    }
+    #[cfg(feature = "tls")]
+    let stream = match &config.tls {
+        Some(tls) => match crate::tls::accept(stream, tls) {
+            Ok(stream) => stream,
+            Err(e) => {
+                stats.record_handshake_failure("tcp_server", &ProtocolError::Io(e).into());
+                return;
+            }
+        },
+        None => stream,
+    };
+    let mut stream = ChunkedTcpStream::new(stream);
+    let params = match handshake::accept(&mut stream, &config.capabilities) {
+        Ok(params) => params,
+        Err(e) => {
+            stats.record_handshake_failure("tcp_server", &e);
+            return;
+        }
+    };
+
+    let stream_clone = match stream.try_clone() {
+        Ok(clone) => clone,
+        Err(e) => {
+            stats.record_close("tcp_server", &ProtocolError::Io(e));
+            return;
+        }
+    };
+    let client_conn = FramedReceiver::<ClientMessage>::new(stream_clone)
+        .with_params(params)
+        .with_max_frame_len(config.max_frame_len);
+    let mut server_conn = FramedSender::<ServerMessage>::new(stream).with_params(params);
+
+    let ending = if config.conn_workers > 1 {
+        serve_out_of_order(client_conn, &mut server_conn, config, stats, shutdown)
+    } else {
+        serve_in_order(client_conn, &mut server_conn, config, stats, shutdown)
+    };
+
+    // Every request received has been answered by now.
+    if ending == Next::Goodbye {
+        if let Err(e) = server_conn.send_msg(&ServerMessage::Goodbye) {
+            stats.record_close("tcp_server", &e);
+        }
+    }
+}
 
+/// Serve requests until the connection ends, returning how it ended.
+fn serve_in_order(
+    mut client_conn: FramedReceiver<ClientMessage>,
+    server_conn: &mut FramedSender<ServerMessage>,
+    config: &ServerConfig,
+    stats: &ServerStats,
+    shutdown: &Shutdown,
+) -> Next {
     loop {
-        let msg = match client_conn.recv_work_msg() {
-            Ok(msg) => msg,
-            Err(e) => {
-                println!("recv_work_msg Error: {}", e);
-                break;
+        let replies = match recv_request(&mut client_conn, stats, shutdown) {
+            Next::Request(msg, received) => {
+                let replies = msg.do_work(config, received);
+                stats.record_replies(msg.len(), &replies);
+                replies
             }
+            Next::Reply(reply) => vec![reply],
+            ending => return ending,
         };
 
-        let reply = msg.do_work();
+        for reply in &replies {
+            if let Err(e) = server_conn.send_msg(reply) {
+                stats.record_close("tcp_server", &e);
+                return Next::Close;
+            }
+        }
+    }
+}
+
+/// Hand requests to `config.conn_workers` threads, which reply as soon as each one
+/// finishes, so a long request doesn't hold up the ones behind it.
+fn serve_out_of_order(
+    mut client_conn: FramedReceiver<ClientMessage>,
+    server_conn: &mut FramedSender<ServerMessage>,
+    config: &ServerConfig,
+    stats: &ServerStats,
+    shutdown: &Shutdown,
+) -> Next {
+    let server_conn = Mutex::new(server_conn);
+    let (jobs_tx, jobs_rx) = mpsc::channel::<(ClientMessage, Instant)>();
+    let jobs_rx = Mutex::new(jobs_rx);
 
-        match server_conn.send_work_msg(reply) {
-            Ok(()) => {
-                continue;
+    thread::scope(|s| {
+        for _ in 0..config.conn_workers {
+            s.spawn(|| loop {
+                let job = jobs_rx.lock().unwrap().recv();
+                let Ok((msg, received)) = job else {
+                    break;
+                };
+
+                let replies = msg.do_work(config, received);
+                stats.record_replies(msg.len(), &replies);
+
+                // A failed send means the connection is gone; the receive loop will see
+                // that and record why.
+                let mut server_conn = server_conn.lock().unwrap();
+                if replies.iter().any(|reply| server_conn.send_msg(reply).is_err()) {
+                    break;
+                }
+            });
+        }
+
+        let ending = loop {
+            match recv_request(&mut client_conn, stats, shutdown) {
+                Next::Request(msg, received) => {
+                    if jobs_tx.send((msg, received)).is_err() {
+                        break Next::Close;
+                    }
+                }
+                Next::Reply(reply) => {
+                    if let Err(e) = server_conn.lock().unwrap().send_msg(&reply) {
+                        stats.record_close("tcp_server", &e);
+                        break Next::Close;
+                    }
+                }
+                ending => break ending,
+            }
+        };
+        // Let the workers drain what's queued and exit.
+        drop(jobs_tx);
+        ending
+    })
+}
+
+/// What the receive side of a connection does next.
+#[derive(Debug, PartialEq)]
+enum Next {
+    /// A request to run, and when it arrived.
+    Request(ClientMessage, Instant),
+    /// A reply to send without running anything.
+    Reply(ServerMessage),
+    /// Answer what has been received, say goodbye and close.
+    Goodbye,
+    /// Close without saying goodbye; the reason has been recorded.
+    Close,
+}
+
+fn recv_request(
+    client_conn: &mut FramedReceiver<ClientMessage>,
+    stats: &ServerStats,
+    shutdown: &Shutdown,
+) -> Next {
+    loop {
+        let payload = client_conn.recv_payload();
+        let received = Instant::now();
+        match payload.map(|p| (ClientMessage::decode(client_conn.codec(), &p), p)) {
+            Ok((Ok(ClientMessage::Goodbye), _)) => {
+                stats.clean_disconnects.fetch_add(1, Ordering::Relaxed);
+                return Next::Goodbye;
+            }
+            Ok((Ok(msg), _)) => return Next::Request(msg, received),
+            // The frame itself was fine, so the connection can carry on.
+            Ok((Err(e), payload)) => {
+                let reply = ClientMessage::undecodable(client_conn.codec(), &payload, &e);
+                stats.record_replies(0, std::slice::from_ref(&reply));
+                return Next::Reply(reply);
+            }
+            // Shutting down stops reads, so this is the error we asked for.
+            Err(_) if shutdown.is_requested() => return Next::Goodbye,
+            Err(e @ ProtocolError::Checksum { .. }) => {
+                stats.record_checksum_failure("tcp_server", &e);
             }
             Err(e) => {
-                eprintln!("send_work_msg error: {}", e);
-                break;
+                stats.record_close("tcp_server", &e);
+                return Next::Close;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod t {
+    use super::handle_conn;
+    use crate::{
+        config::ServerConfig,
+        shutdown::{drains_on_shutdown, drains_shm_on_shutdown},
+    };
+
+    #[test]
+    fn drains_in_order_on_shutdown() {
+        drains_on_shutdown(handle_conn, ServerConfig::default());
+    }
+
+    #[test]
+    fn drains_workers_on_shutdown() {
+        drains_on_shutdown(
+            handle_conn,
+            ServerConfig {
+                conn_workers: 4,
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn drains_shm_in_order_on_shutdown() {
+        drains_shm_on_shutdown(handle_conn, ServerConfig::default());
+    }
+}
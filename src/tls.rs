@@ -0,0 +1,303 @@
+//! TLS over TCP, with [rustls], for measuring what encryption adds to small RPCs. Only
+//! built with the `tls` cargo feature.
+//!
+//! A [`TlsStream`] can be cloned like a socket, so one thread can send while another
+//! blocks receiving. The handles share one TLS session behind a mutex, but a receiving
+//! handle reads from the socket without holding it.
+
+use crate::transport::Stream;
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    ClientConnection, Connection, RootCertStore, ServerConnection,
+};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// Bytes read from the socket at a time; one TLS record at most.
+const RECORD_LEN: usize = 16 * 1024 + 256;
+
+/// What a client needs to open TLS connections.
+#[derive(Debug, Clone)]
+pub struct TlsClient {
+    pub config: Arc<rustls::ClientConfig>,
+    /// The name the server's certificate must be valid for.
+    pub server_name: ServerName<'static>,
+}
+
+impl TlsClient {
+    /// Trust the certificates in the PEM file at `ca`, and expect the server to present
+    /// one valid for `server_name`, a host name or an IP address.
+    pub fn from_pem(ca: &Path, server_name: &str) -> io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(ca).map_err(invalid_data)? {
+            roots.add(cert.map_err(invalid_data)?).map_err(invalid_data)?;
+        }
+        let server_name = ServerName::try_from(server_name.to_string()).map_err(invalid_data)?;
+        Ok(Self::new(roots, server_name))
+    }
+
+    pub fn new(roots: RootCertStore, server_name: ServerName<'static>) -> Self {
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Self {
+            config: Arc::new(config),
+            server_name,
+        }
+    }
+}
+
+/// Server configuration presenting the certificate chain in the PEM file at `cert`, with
+/// the private key in the PEM file at `key`.
+pub fn server_config_from_pem(cert: &Path, key: &Path) -> io::Result<Arc<rustls::ServerConfig>> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .map_err(invalid_data)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid_data)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(invalid_data)?;
+    server_config(chain, key)
+}
+
+pub fn server_config(
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> io::Result<Arc<rustls::ServerConfig>> {
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .map_err(invalid_data)?;
+    Ok(Arc::new(config))
+}
+
+/// Run the client side of a TLS handshake over `stream`, which must be TCP.
+pub fn connect(stream: Stream, client: &TlsClient) -> io::Result<Stream> {
+    let conn = ClientConnection::new(client.config.clone(), client.server_name.clone())
+        .map_err(invalid_data)?;
+    TlsStream::handshake(into_tcp(stream)?, conn.into()).map(Stream::Tls)
+}
+
+/// Run the server side of a TLS handshake over `stream`, which must be TCP.
+pub fn accept(stream: Stream, config: &Arc<rustls::ServerConfig>) -> io::Result<Stream> {
+    let conn = ServerConnection::new(config.clone()).map_err(invalid_data)?;
+    TlsStream::handshake(into_tcp(stream)?, conn.into()).map(Stream::Tls)
+}
+
+fn into_tcp(stream: Stream) -> io::Result<TcpStream> {
+    match stream {
+        Stream::Tcp(s) => Ok(s),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TLS needs a TCP connection",
+        )),
+    }
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// One handle to a TLS connection; see the module docs.
+pub struct TlsStream {
+    session: Arc<Mutex<Session>>,
+    /// This handle's own socket, read without holding the session lock.
+    sock: TcpStream,
+    record: Box<[u8]>,
+}
+
+struct Session {
+    conn: Connection,
+    sock: TcpStream,
+}
+
+impl Session {
+    /// Send whatever TLS records are waiting.
+    fn flush_tls(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.sock)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Lets the peer tell a clean close from a truncated connection.
+        self.conn.send_close_notify();
+        let _ = self.flush_tls();
+    }
+}
+
+impl TlsStream {
+    fn handshake(mut sock: TcpStream, mut conn: Connection) -> io::Result<Self> {
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+        let mut session = Session {
+            conn,
+            sock: sock.try_clone()?,
+        };
+        session.flush_tls()?;
+        Ok(Self {
+            session: Arc::new(Mutex::new(session)),
+            sock,
+            record: vec![0; RECORD_LEN].into_boxed_slice(),
+        })
+    }
+
+    /// Another handle to the same connection.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            session: self.session.clone(),
+            sock: self.sock.try_clone()?,
+            record: vec![0; RECORD_LEN].into_boxed_slice(),
+        })
+    }
+
+    /// The underlying socket, for timeouts and shutdown.
+    pub fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+}
+
+impl fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsStream").field("sock", &self.sock).finish()
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.session.lock().unwrap().conn.reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                r => return r,
+            }
+
+            let n = self.sock.read(&mut self.record)?;
+            let mut session = self.session.lock().unwrap();
+            let mut record = &self.record[..n];
+            // An empty read tells rustls the socket is at end of file.
+            loop {
+                session.conn.read_tls(&mut record)?;
+                session.conn.process_new_packets().map_err(invalid_data)?;
+                if record.is_empty() {
+                    break;
+                }
+            }
+            // Answer anything the peer's records asked for, such as a key update.
+            session.flush_tls()?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let n = session.conn.writer().write(buf)?;
+        session.flush_tls()?;
+        Ok(n)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let n = session.conn.writer().write_vectored(bufs)?;
+        session.flush_tls()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.lock().unwrap().flush_tls()
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::{accept, connect, server_config, TlsClient};
+    use crate::{
+        app::Work,
+        chunked_tcp_stream::ChunkedTcpStream,
+        protocol::FramedConn,
+        serialize::ClientWorkPacket,
+        transport::Stream,
+    };
+    use rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+        RootCertStore,
+    };
+    use std::{net::TcpListener, thread};
+
+    /// A certificate for `localhost`, signed by itself, and its key.
+    fn self_signed() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+        (cert.cert.der().clone(), key.into())
+    }
+
+    fn client_trusting(cert: &CertificateDer<'static>) -> TlsClient {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.clone()).unwrap();
+        TlsClient::new(roots, ServerName::try_from("localhost").unwrap())
+    }
+
+    fn loopback_pair() -> (Stream, Stream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = Stream::connect(&crate::transport::Endpoint::Tcp(listener.local_addr().unwrap()))
+            .unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server.into())
+    }
+
+    #[test]
+    fn tls_framed_bounce() {
+        let (cert, key) = self_signed();
+        let client_tls = client_trusting(&cert);
+        let server_tls = server_config(vec![cert], key).unwrap();
+        let (client, server) = loopback_pair();
+
+        let server = thread::spawn(move || {
+            let stream = accept(server, &server_tls).unwrap();
+            let (mut tx, mut rx) = FramedConn::<ClientWorkPacket>::new(ChunkedTcpStream::new(stream))
+                .split()
+                .unwrap();
+            // Echo on the sending half while the receiving half's clone sits idle.
+            for _ in 0..3 {
+                let msg = rx.recv_msg().unwrap();
+                tx.send_msg(&msg).unwrap();
+            }
+        });
+
+        let stream = connect(client, &client_tls).unwrap();
+        let (mut tx, mut rx) = FramedConn::<ClientWorkPacket>::new(ChunkedTcpStream::new(stream))
+            .split()
+            .unwrap();
+        let receiver = thread::spawn(move || (0..3).map(|_| rx.recv_msg().unwrap()).collect::<Vec<_>>());
+        let sent: Vec<_> = (0..3).map(|id| ClientWorkPacket::new(id, Work::Immediate)).collect();
+        for msg in &sent {
+            tx.send_msg(msg).unwrap();
+        }
+        assert_eq!(receiver.join().unwrap(), sent);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn tls_rejects_untrusted_server() {
+        let (cert, key) = self_signed();
+        let (other, _) = self_signed();
+        let server_tls = server_config(vec![cert], key).unwrap();
+        let (client, server) = loopback_pair();
+
+        let server = thread::spawn(move || accept(server, &server_tls).is_err());
+        assert!(connect(client, &client_trusting(&other)).is_err());
+        assert!(server.join().unwrap());
+    }
+}
@@ -0,0 +1,512 @@
+//! The byte streams the protocol can run over: TCP, `AF_UNIX` stream sockets on the same
+//! host, or an in-memory [`MemPipe`] for tests and simulations that shouldn't open ports.
+
+use crate::shm::{self, ShmStream};
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::{
+            fs::FileTypeExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::PathBuf,
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+/// A reliable, ordered, bidirectional byte stream. [`ChunkedTcpStream`] and the framed
+/// connection types in [`crate::protocol`] work over any of these.
+///
+/// [`ChunkedTcpStream`]: crate::chunked_tcp_stream::ChunkedTcpStream
+pub trait Transport: Read + Write + Send + Sized + 'static {
+    /// Another handle to the same stream, so one thread can send while another receives.
+    fn try_clone(&self) -> io::Result<Self>;
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+impl Transport for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
+
+impl Transport for Stream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Stream::try_clone(self)
+    }
+}
+
+/// Where a server listens, or where a client connects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    /// Path of a Unix domain stream socket.
+    Unix(PathBuf),
+    /// Path of the Unix domain socket a shared-memory server takes new clients on.
+    Shm(PathBuf),
+}
+
+impl Endpoint {
+    /// A TCP endpoint for `host`, which can be a name, an IPv4 address or an IPv6 address
+    /// (without brackets). A name that resolves to several addresses uses the first.
+    pub fn resolve(host: &str, port: u16) -> io::Result<Self> {
+        (host, port)
+            .to_socket_addrs()?
+            .next()
+            .map(Self::Tcp)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} has no addresses", host),
+                )
+            })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Shm(path) => write!(f, "shm:{}", path.display()),
+        }
+    }
+}
+
+/// A connected stream socket of either kind, or a TLS connection over TCP.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    /// Its raw file descriptor is the control socket's; bytes written there bypass the
+    /// shared memory rings.
+    Shm(ShmStream),
+    /// Its raw file descriptor is the TCP socket's; bytes written there bypass TLS.
+    #[cfg(feature = "tls")]
+    Tls(crate::tls::TlsStream),
+}
+
+impl Stream {
+    pub fn connect(endpoint: &Endpoint) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::Tcp(addr) => TcpStream::connect(addr).map(Self::Tcp),
+            Endpoint::Unix(path) => UnixStream::connect(path).map(Self::Unix),
+            Endpoint::Shm(path) => shm::connect(path).map(Self::Shm),
+        }
+    }
+
+    /// Another handle to the same underlying socket.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(s) => s.try_clone().map(Self::Tcp),
+            Self::Unix(s) => s.try_clone().map(Self::Unix),
+            Self::Shm(s) => Ok(Self::Shm(s.clone())),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.try_clone().map(Self::Tls),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.set_read_timeout(timeout),
+            Self::Unix(s) => s.set_read_timeout(timeout),
+            Self::Shm(s) => s.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.socket().set_read_timeout(timeout),
+        }
+    }
+
+    /// Disable Nagle's algorithm. Unix sockets and shared memory don't batch small writes,
+    /// so this does nothing for them.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.set_nodelay(nodelay),
+            Self::Unix(_) | Self::Shm(_) => Ok(()),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.socket().set_nodelay(nodelay),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.shutdown(how),
+            Self::Unix(s) => s.shutdown(how),
+            Self::Shm(s) => s.shutdown(how),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.socket().shutdown(how),
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(value: TcpStream) -> Self {
+        Self::Tcp(value)
+    }
+}
+
+impl From<UnixStream> for Stream {
+    fn from(value: UnixStream) -> Self {
+        Self::Unix(value)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.read(buf),
+            Self::Unix(s) => s.read(buf),
+            Self::Shm(s) => s.read(buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.read(buf),
+        }
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.read_vectored(bufs),
+            Self::Unix(s) => s.read_vectored(bufs),
+            Self::Shm(s) => s.read_vectored(bufs),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.read_vectored(bufs),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.write(buf),
+            Self::Unix(s) => s.write(buf),
+            Self::Shm(s) => s.write(buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(s) => s.write_vectored(bufs),
+            Self::Unix(s) => s.write_vectored(bufs),
+            Self::Shm(s) => s.write_vectored(bufs),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.flush(),
+            Self::Unix(s) => s.flush(),
+            Self::Shm(s) => s.flush(),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(s) => s.as_raw_fd(),
+            Self::Unix(s) => s.as_raw_fd(),
+            Self::Shm(s) => s.control().as_raw_fd(),
+            #[cfg(feature = "tls")]
+            Self::Tls(s) => s.socket().as_raw_fd(),
+        }
+    }
+}
+
+/// A listening socket of either kind. A shared-memory listener is a Unix one that sets up
+/// a shared-memory connection with each client it accepts.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+    Shm(UnixListener),
+}
+
+impl Listener {
+    /// Listen on `endpoint`. A socket file left behind by an earlier run at the same path
+    /// is removed first; any other kind of file there is an error.
+    pub fn bind(endpoint: &Endpoint) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::Tcp(addr) => TcpListener::bind(addr).map(Self::Tcp),
+            Endpoint::Unix(path) | Endpoint::Shm(path) => {
+                match std::fs::symlink_metadata(path) {
+                    Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+                    Ok(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("{} exists and is not a socket", path.display()),
+                        ))
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+                let listener = UnixListener::bind(path)?;
+                Ok(match endpoint {
+                    Endpoint::Shm(_) => Self::Shm(listener),
+                    _ => Self::Unix(listener),
+                })
+            }
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Accepted> {
+        match self {
+            Self::Tcp(l) => l.accept().map(|(s, _)| Accepted::Ready(Stream::Tcp(s))),
+            Self::Unix(l) => l.accept().map(|(s, _)| Accepted::Ready(Stream::Unix(s))),
+            Self::Shm(l) => l.accept().map(|(s, _)| Accepted::Shm(s)),
+        }
+    }
+
+    /// Accepted connections, forever.
+    pub fn incoming(&self) -> impl Iterator<Item = io::Result<Accepted>> + '_ {
+        std::iter::repeat_with(|| self.accept())
+    }
+}
+
+/// A connection just taken off a [`Listener`]. Setup that waits on the client is left to
+/// [`Self::establish`], so that one silent client can't hold up the accept loop.
+#[derive(Debug)]
+pub enum Accepted {
+    Ready(Stream),
+    /// A shared-memory client's control socket, before it has sent its rings.
+    Shm(UnixStream),
+}
+
+impl Accepted {
+    /// The connection, once any setup is done, giving the client up to `timeout` for its
+    /// part.
+    pub fn establish(self, timeout: Option<Duration>) -> io::Result<Stream> {
+        match self {
+            Self::Ready(stream) => Ok(stream),
+            Self::Shm(control) => {
+                control.set_read_timeout(timeout)?;
+                let stream = shm::accept(control)?;
+                stream.control().set_read_timeout(None)?;
+                Ok(Stream::Shm(stream))
+            }
+        }
+    }
+}
+
+/// One end of an in-memory duplex byte stream; see [`MemPipe::pair`].
+///
+/// Writes never block: each direction buffers without limit. Reads block until there is
+/// data, or return end-of-file once every handle to the other end has been dropped.
+/// Writing after every handle to the other end has been dropped fails with `BrokenPipe`.
+#[derive(Debug)]
+pub struct MemPipe {
+    rx: Arc<PipeHalf>,
+    tx: Arc<PipeHalf>,
+}
+
+/// The bytes travelling in one direction, and who can still read or write them.
+#[derive(Debug, Default)]
+struct PipeHalf {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PipeState {
+    bytes: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+impl MemPipe {
+    /// Two connected ends: what one writes, the other reads.
+    pub fn pair() -> (Self, Self) {
+        let a_to_b = Arc::new(PipeHalf::default());
+        let b_to_a = Arc::new(PipeHalf::default());
+        (
+            Self::open(b_to_a.clone(), a_to_b.clone()),
+            Self::open(a_to_b, b_to_a),
+        )
+    }
+
+    fn open(rx: Arc<PipeHalf>, tx: Arc<PipeHalf>) -> Self {
+        rx.state.lock().unwrap().readers += 1;
+        tx.state.lock().unwrap().writers += 1;
+        Self { rx, tx }
+    }
+}
+
+impl Transport for MemPipe {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self::open(self.rx.clone(), self.tx.clone()))
+    }
+}
+
+impl Read for MemPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.rx.state.lock().unwrap();
+        while state.bytes.is_empty() && state.writers > 0 && !buf.is_empty() {
+            state = self.rx.readable.wait(state).unwrap();
+        }
+        let n = buf.len().min(state.bytes.len());
+        for (dst, src) in buf.iter_mut().zip(state.bytes.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        let wanted: usize = bufs.iter().map(|buf| buf.len()).sum();
+        let mut state = self.rx.state.lock().unwrap();
+        while state.bytes.is_empty() && state.writers > 0 && wanted > 0 {
+            state = self.rx.readable.wait(state).unwrap();
+        }
+        let n = wanted.min(state.bytes.len());
+        let mut bytes = state.bytes.drain(..n);
+        for buf in bufs.iter_mut() {
+            for (dst, src) in buf.iter_mut().zip(&mut bytes) {
+                *dst = src;
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.tx.state.lock().unwrap();
+        if state.readers == 0 {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.bytes.extend(buf);
+        self.tx.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let mut state = self.tx.state.lock().unwrap();
+        if state.readers == 0 {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        for buf in bufs {
+            state.bytes.extend(buf.iter());
+        }
+        self.tx.readable.notify_all();
+        Ok(bufs.iter().map(|buf| buf.len()).sum())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MemPipe {
+    fn drop(&mut self) {
+        self.rx.state.lock().unwrap().readers -= 1;
+        self.tx.state.lock().unwrap().writers -= 1;
+        // Wake a reader at the other end so it sees end-of-file.
+        self.tx.readable.notify_all();
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(l) => l.as_raw_fd(),
+            Self::Unix(l) | Self::Shm(l) => l.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::{Endpoint, Listener, MemPipe, Stream, Transport};
+    use std::{
+        io::{self, Read, Write},
+        net::{Ipv6Addr, SocketAddr},
+        os::unix::net::UnixStream,
+        time::Duration,
+    };
+
+    #[test]
+    fn resolve_addresses_and_names() {
+        let v6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 7000);
+        assert_eq!(Endpoint::resolve("::1", 7000).unwrap(), Endpoint::Tcp(v6));
+        assert_eq!(Endpoint::Tcp(v6).to_string(), "[::1]:7000");
+        let Endpoint::Tcp(local) = Endpoint::resolve("localhost", 7000).unwrap() else {
+            panic!("a host name resolves to a TCP endpoint");
+        };
+        assert!(local.ip().is_loopback());
+    }
+
+    #[test]
+    fn mem_pipe_bounce_and_close() {
+        let (mut a, b) = MemPipe::pair();
+        let mut b_rx = b.try_clone().unwrap();
+        a.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        b_rx.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // End-of-file only once every handle to the writing end is gone.
+        let mut a_clone = a.try_clone().unwrap();
+        drop(a);
+        a_clone.write_all(b"!").unwrap();
+        drop(a_clone);
+        let mut rest = Vec::new();
+        b_rx.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"!");
+
+        drop((b, b_rx));
+        let (mut a, b) = MemPipe::pair();
+        drop(b);
+        assert_eq!(a.write(b"x").unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn unix_bounce_and_rebind() {
+        let path = std::env::temp_dir().join(format!("woonsocket-{}.sock", std::process::id()));
+        let endpoint = Endpoint::Unix(path.clone());
+
+        // Binding twice works, as after a server that didn't clean up.
+        drop(Listener::bind(&endpoint).unwrap());
+        let listener = Listener::bind(&endpoint).unwrap();
+
+        let mut client = Stream::connect(&endpoint).unwrap();
+        let mut server = listener.accept().unwrap().establish(None).unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn silent_shm_client_does_not_hold_up_accepts() {
+        let path = std::env::temp_dir().join(format!("woonsocket-shm-{}.sock", std::process::id()));
+        let endpoint = Endpoint::Shm(path.clone());
+        let listener = Listener::bind(&endpoint).unwrap();
+
+        let _silent = UnixStream::connect(&path).unwrap();
+        let silent = listener.accept().unwrap();
+        let mut client = Stream::connect(&endpoint).unwrap();
+        let mut server = listener.accept().unwrap().establish(None).unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let err = silent.establish(Some(Duration::from_millis(20))).unwrap_err();
+        assert!(matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut), "{}", err);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+//! Open-loop load over UDP, one request per datagram. Unlike TCP, nothing pushes back on
+//! the sender: requests go out on schedule however far behind the server is, and whatever
+//! the network or the server's socket buffer drops is lost. A request counts as lost if
+//! its response doesn't arrive within `ClientConfig::response_timeout` of sending it.
+
+use crate::{
+    app::Work,
+    config::ClientConfig,
+    get_current_time_micros,
+    serialize::{ClientMessage, ClientWorkPacket, LatencyRecord, MessageTrait, ServerMessage},
+    stats::ClientStats,
+    udp_server::MAX_DATAGRAM_LEN,
+};
+
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use csv::Writer;
+use minstant::Instant;
+
+fn client_send_loop(
+    socket: UdpSocket,
+    thread_delay: Duration,
+    runtime: Duration,
+    packets_sent: Arc<AtomicU64>,
+    work: Work,
+) {
+    let start = Instant::now();
+    let end_time = start + runtime;
+    let mut next_send_time = start;
+    let mut buf = Vec::new();
+
+    for id in 0.. {
+        // Absolute pacing: a late send doesn't push back the ones after it.
+        next_send_time += thread_delay;
+        let now = Instant::now();
+        if next_send_time > now {
+            thread::sleep(next_send_time - now);
+        }
+        if Instant::now() >= end_time {
+            break;
+        }
+
+        buf.clear();
+        ClientMessage::Work(ClientWorkPacket::new(id, work))
+            .to_vec(&mut buf)
+            .unwrap();
+        // A full socket buffer drops the datagram, which is the behaviour under study;
+        // the receiver will count it as lost.
+        let _ = socket.send(&buf);
+        packets_sent.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn client_recv_loop(
+    socket: UdpSocket,
+    sender_done: Arc<AtomicBool>,
+    packets_sent: Arc<AtomicU64>,
+    response_timeout: Duration,
+    stats: Arc<ClientStats>,
+) -> Vec<LatencyRecord> {
+    let mut latencies = Vec::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+    let mut answered = HashSet::new();
+    let mut highest_id = None;
+    let timeout_us = response_timeout.as_micros() as u64;
+    // Once the sender stops, its last request has until then to be answered.
+    let mut give_up_at = None;
+
+    loop {
+        if give_up_at.is_none() && sender_done.load(Ordering::SeqCst) {
+            give_up_at = Some(Instant::now() + response_timeout);
+        }
+        let sent = packets_sent.load(Ordering::SeqCst);
+        if give_up_at.is_some_and(|t| Instant::now() >= t || answered.len() as u64 >= sent) {
+            break;
+        }
+
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            // Typically ECONNREFUSED, reported for an earlier datagram nobody received.
+            Err(e) => {
+                eprintln!("udp receive error: {}", e);
+                continue;
+            }
+        };
+        let recv_time = get_current_time_micros();
+        let packets = match ServerMessage::from_bytes(&buf[..len]) {
+            Ok(msg) => msg.into_packets(),
+            Err(e) => {
+                eprintln!("dropped undecodable response: {}", e);
+                continue;
+            }
+        };
+
+        for packet in packets {
+            let id = packet.client_id();
+            if recv_time.saturating_sub(packet.client_send_time()) > timeout_us {
+                // Too late: already given up on, so it stays counted as lost.
+                continue;
+            }
+            if !answered.insert(id) {
+                stats.unmatched_responses.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            if highest_id.is_some_and(|highest| id < highest) {
+                stats.reordered_responses.fetch_add(1, Ordering::Relaxed);
+            }
+            highest_id = highest_id.max(Some(id));
+
+            // Failed responses are counted by code and have no latency.
+            if !stats.record_failure(&packet) {
+                if let Some(lat) = packet.calculate_latency(recv_time) {
+                    latencies.push(lat);
+                }
+            }
+        }
+    }
+
+    let sent = packets_sent.load(Ordering::SeqCst);
+    stats
+        .lost_responses
+        .fetch_add(sent.saturating_sub(answered.len() as u64), Ordering::Relaxed);
+    latencies
+}
+
+fn init_client(
+    server_addr: SocketAddr,
+    thread_delay: Duration,
+    runtime: Duration,
+    work: Work,
+    config: &ClientConfig,
+    stats: Arc<ClientStats>,
+) -> JoinHandle<Vec<LatencyRecord>> {
+    let any = match server_addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(any).expect("Couldn't bind UDP socket");
+    socket.connect(server_addr).expect("Couldn't connect UDP socket");
+    config
+        .socket
+        .apply_udp(&socket)
+        .expect("Failed to set socket options")
+        .log_once("client");
+    // Wake up regularly to notice that the run is over.
+    socket
+        .set_read_timeout(Some(config.heartbeat_interval.min(config.response_timeout)))
+        .expect("Failed to set read timeout");
+
+    let sent = Arc::new(AtomicU64::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+    {
+        let socket = socket.try_clone().expect("Failed to clone socket");
+        let sent = sent.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            client_send_loop(socket, thread_delay, runtime, sent, work);
+            done.store(true, Ordering::SeqCst);
+        });
+    }
+
+    let response_timeout = config.response_timeout;
+    thread::spawn(move || client_recv_loop(socket, done, sent, response_timeout, stats))
+}
+
+pub fn run(
+    server_addr: SocketAddr,
+    num_threads: usize,
+    interarrival: Duration,
+    runtime: Duration,
+    work: Work,
+    outpath: PathBuf,
+    config: ClientConfig,
+) {
+    let thread_delay = interarrival * (num_threads as u32);
+
+    println!("start: thread_delay {:?}", thread_delay);
+    let stats = Arc::new(ClientStats::default());
+    let join_handles: Vec<_> = (0..num_threads)
+        .map(|_| init_client(server_addr, thread_delay, runtime, work, &config, stats.clone()))
+        .collect();
+
+    let request_latencies: Vec<Vec<LatencyRecord>> =
+        join_handles.into_iter().map(|h| h.join().unwrap()).collect();
+    println!("client stats: {}", stats);
+
+    let mut w = Writer::from_path(&outpath).expect("Failed to create CSV writer");
+    w.write_record(["idx", "send_us", "recv_us", "server_proc_us", "latency_us"]).unwrap();
+    for thread_recs in request_latencies {
+        for (i, rec) in thread_recs.iter().enumerate() {
+            w.write_record(&[
+                i.to_string(),
+                rec.send_timestamp.to_string(),
+                rec.recv_timestamp.to_string(),
+                rec.server_processing_time.to_string(),
+                rec.latency.to_string(),
+            ])
+            .unwrap();
+        }
+    }
+    w.flush().unwrap();
+}
+
+#[cfg(test)]
+mod t {
+    use super::init_client;
+    use crate::{
+        app::Work,
+        config::{ClientConfig, ServerConfig},
+        serialize::{MessageTrait, ServerMessage},
+        shutdown::Shutdown,
+        stats::{ClientStats, ServerStats},
+        udp_server::{self, MAX_DATAGRAM_LEN},
+    };
+    use std::{
+        io,
+        net::UdpSocket,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    /// Relay requests to `server` untouched, and replies back with the one to request 1
+    /// dropped, the one to 2 sent twice, and the ones to 3 and 4 swapped. Stops soon after
+    /// `stop` is set.
+    fn lossy_relay(shim: UdpSocket, server: &UdpSocket, stop: &AtomicBool) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+        let mut client = None;
+        let mut held = None;
+        shim.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        while !stop.load(Ordering::Relaxed) {
+            let (len, from) = match shim.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                Err(e) => panic!("relay failed: {}", e),
+            };
+            if from != server.local_addr().unwrap() {
+                client = Some(from);
+                let _ = shim.send_to(&buf[..len], server.local_addr().unwrap());
+                continue;
+            }
+            let Some(client) = client else { continue };
+            let reply = &buf[..len];
+            let id = match ServerMessage::from_bytes(reply).unwrap() {
+                ServerMessage::Work(packet) => packet.client_id(),
+                other => panic!("unexpected reply {:?}", other),
+            };
+            match id {
+                1 => {}
+                2 => {
+                    shim.send_to(reply, client).unwrap();
+                    shim.send_to(reply, client).unwrap();
+                }
+                3 => held = Some(reply.to_vec()),
+                4 => {
+                    shim.send_to(reply, client).unwrap();
+                    shim.send_to(&held.take().unwrap(), client).unwrap();
+                }
+                _ => {
+                    shim.send_to(reply, client).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn counts_lost_repeated_and_reordered_responses() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let shim = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let shim_addr = shim.local_addr().unwrap();
+        server.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let (shutdown, stop) = (Shutdown::default(), AtomicBool::new(false));
+
+        let config = ClientConfig {
+            heartbeat_interval: Duration::from_millis(50),
+            response_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let stats = Arc::new(ClientStats::default());
+        let client = init_client(
+            shim_addr,
+            Duration::from_millis(5),
+            Duration::from_millis(100),
+            Work::Immediate,
+            &config,
+            stats.clone(),
+        );
+        let latencies = thread::scope(|s| {
+            s.spawn(|| {
+                let socket = server.try_clone().unwrap();
+                udp_server::serve(socket, &ServerConfig::default(), &ServerStats::default(), &shutdown)
+            });
+            s.spawn(|| lossy_relay(shim, &server, &stop));
+            let latencies = client.join();
+            shutdown.begin();
+            stop.store(true, Ordering::Relaxed);
+            latencies.unwrap()
+        });
+
+        assert!(latencies.len() >= 4, "only {} answered", latencies.len());
+        assert_eq!(stats.lost_responses.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.unmatched_responses.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.reordered_responses.load(Ordering::Relaxed), 1);
+    }
+}
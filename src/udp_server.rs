@@ -0,0 +1,110 @@
+//! A server that takes each request in its own UDP datagram and answers each in a datagram
+//! of its own. There is no handshake, framing or flow control: a datagram carries exactly
+//! one bincode-serialized [`ClientMessage`], and a lost one is simply lost.
+
+use crate::{
+    config::ServerConfig,
+    handshake::Codec,
+    serialize::{ClientMessage, MessageTrait, ServerMessage},
+    shutdown::Shutdown,
+    stats::ServerStats,
+};
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Largest UDP payload; nothing bigger can arrive.
+pub const MAX_DATAGRAM_LEN: usize = 65_507;
+
+/// How long a worker waits for a datagram before checking for shutdown.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/// Serve on `addr` with `config.conn_workers` threads sharing one socket, until shutdown
+/// begins.
+pub fn udp_server(
+    addr: SocketAddr,
+    config: ServerConfig,
+    stats: Arc<ServerStats>,
+    shutdown: Arc<Shutdown>,
+) {
+    let socket = UdpSocket::bind(addr).expect("failed to bind UDP socket");
+    config
+        .socket
+        .apply_udp(&socket)
+        .expect("failed to set socket options")
+        .log_once("udp_server");
+    socket
+        .set_read_timeout(Some(SHUTDOWN_POLL))
+        .expect("failed to set read timeout");
+    println!("udp_server listening on {}", addr);
+
+    let workers: Vec<_> = (0..config.conn_workers.max(1))
+        .map(|_| {
+            let socket = socket.try_clone().expect("failed to clone UDP socket");
+            let config = config.clone();
+            let stats = stats.clone();
+            let shutdown = shutdown.clone();
+            thread::spawn(move || serve(socket, &config, &stats, &shutdown))
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+}
+
+/// Answer datagrams on `socket` until shutdown begins, which is noticed when a receive
+/// returns or times out.
+pub(crate) fn serve(socket: UdpSocket, config: &ServerConfig, stats: &ServerStats, shutdown: &Shutdown) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+    let mut out = Vec::new();
+    while !shutdown.is_requested() {
+        let (len, peer) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                stats.io_errors.fetch_add(1, Ordering::Relaxed);
+                eprintln!("[udp_server] recv error: {}", e);
+                continue;
+            }
+        };
+        let received = Instant::now();
+
+        let replies = match ClientMessage::from_bytes(&buf[..len]) {
+            // Without a connection there is nothing to say goodbye to.
+            Ok(ClientMessage::Goodbye) => continue,
+            Ok(request) => {
+                let replies = request.do_work(config, received);
+                stats.record_replies(request.len(), &replies);
+                replies
+            }
+            Err(e) => {
+                let reply = ClientMessage::undecodable(Codec::Bincode, &buf[..len], &e);
+                stats.record_replies(0, std::slice::from_ref(&reply));
+                vec![reply]
+            }
+        };
+
+        for reply in &replies {
+            if let Err(e) = send_reply(&socket, peer, reply, &mut out) {
+                stats.io_errors.fetch_add(1, Ordering::Relaxed);
+                eprintln!("[udp_server] send to {} failed: {}", peer, e);
+            }
+        }
+    }
+}
+
+fn send_reply(
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    reply: &ServerMessage,
+    out: &mut Vec<u8>,
+) -> Result<(), anyhow::Error> {
+    out.clear();
+    reply.to_vec(out)?;
+    socket.send_to(out, peer)?;
+    Ok(())
+}